@@ -7,14 +7,9 @@ cd iocost-benchmarks-tools
 cargo build -r
 cd ..
 
-# Build resctl-bench and copy the binary to the expected path
-cd resctl-demo
-cargo build -p resctl-bench -r
-cd ../iocost-benchmarks
-mkdir -p resctl-demo-v2.2
-cp ../resctl-demo/target/release/resctl-bench resctl-demo-v2.2
-
-# Merge the database results and generate the hwdb file
+# Merge the database results and generate the hwdb file. Results of the
+# resctl-bench version linked into the tool are merged in-process.
+cd iocost-benchmarks
 ../iocost-benchmarks-tools/target/release/merge-results
 
 cd ..
@@ -0,0 +1,193 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use iocost_benchmarks::common::{
+    database_directory, run_resctl, BenchFWMerge, BenchMerge, BenchVersion, FWMergeOpts,
+    ResultInfo, ResultSet,
+};
+
+/// Bump when the cached files or the way they are generated change, to
+/// invalidate existing caches.
+const CACHE_FORMAT: u32 = 6;
+
+/// Returns the md5 of the file in `path`.
+fn file_md5(path: &Path) -> Result<String> {
+    let contents = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    Ok(format!("{:x}", md5::compute(contents)))
+}
+
+/// Merge info saved in a cache entry next to the merged results
+#[derive(Deserialize, Serialize)]
+struct CachedMerge {
+    data_points: usize,
+    low_confidence: Vec<String>,
+    hwdb: Option<String>,
+    fwmerges: Vec<CachedFWMerge>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct CachedFWMerge {
+    fwrev: String,
+    data_points: usize,
+    low_confidence: Vec<String>,
+    hwdb: String,
+}
+
+/// Cache of the merges done by merge-results, with one entry per
+/// resctl-bench version and model. An entry is only reused if the key
+/// computed from the result files and the resctl-bench version is the
+/// same, so only the models whose inputs changed are merged again.
+/// Laid out as:
+/// <dir>/<version>/<model>/
+///   - key
+///   - merge.json
+///   - merged-results.json.gz
+///   - fwrev-merged-results-<index>.json.gz
+///   - merged.pdf
+pub struct MergeCache {
+    dir: PathBuf,
+}
+
+impl MergeCache {
+    pub fn new(dir: &Path) -> Self {
+        MergeCache {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn entry_dir(&self, version: &str, model_name: &str) -> PathBuf {
+        self.dir.join(version).join(model_name)
+    }
+
+    /// Returns the path of the cached pdf of a merge.
+    pub fn pdf_path(&self, version: &str, model_name: &str) -> PathBuf {
+        self.entry_dir(version, model_name).join("merged.pdf")
+    }
+
+    fn fwmerge_path(dir: &Path, index: usize) -> PathBuf {
+        dir.join(format!("fwrev-merged-results-{}.json.gz", index))
+    }
+
+    /// Returns the cache key for merging the result files in `inputs`
+    /// with resctl-bench `version`, `fwmerge_opts` and `bootstrap`
+    /// resamples. The key covers the versions of this crate and of the
+    /// linked resctl-bench, and the md5 of the resctl-bench binary for
+    /// external versions.
+    pub fn key(
+        version: &BenchVersion,
+        inputs: &[PathBuf],
+        fwmerge_opts: &FWMergeOpts,
+        bootstrap: u32,
+    ) -> Result<String> {
+        let bench = if version.native {
+            format!("resctl-bench {} (native)", *resctl_bench::VERSION)
+        } else {
+            format!(
+                "{} {}",
+                run_resctl(&version.major_minor, &["--version"])?.trim(),
+                file_md5(&version.resctl_bench)?
+            )
+        };
+        let mut key = format!(
+            "format {}\n{} {}\n{}\nhwdb {} hwdb-fwrev {}\n{:?}\nbootstrap {}\n",
+            CACHE_FORMAT,
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            bench,
+            version.hwdb,
+            version.hwdb_fwrev,
+            fwmerge_opts,
+            bootstrap
+        );
+        for input in inputs {
+            let contents = fs::read(input).with_context(|| format!("Failed to read {:?}", input))?;
+            key.push_str(&format!(
+                "{} {:x}\n",
+                input.file_name().unwrap().to_string_lossy(),
+                md5::compute(contents)
+            ));
+        }
+        Ok(format!("{:x}", md5::compute(key)))
+    }
+
+    /// Returns the cached merge of `model_name` results of resctl-bench
+    /// `version` if its key is `key`.
+    pub fn load(&self, version: &str, model_name: &str, key: &str) -> Result<Option<BenchMerge>> {
+        let dir = self.entry_dir(version, model_name);
+        match fs::read_to_string(dir.join("key")) {
+            Ok(cached_key) if cached_key == key => {}
+            _ => return Ok(None),
+        }
+
+        let cached: CachedMerge = serde_json::from_str(&fs::read_to_string(dir.join("merge.json"))?)?;
+        let fwmerges = cached
+            .fwmerges
+            .into_iter()
+            .enumerate()
+            .map(|(index, fwmerge)| {
+                Ok(BenchFWMerge {
+                    fwrev: fwmerge.fwrev,
+                    merged: ResultSet::load(version, &Self::fwmerge_path(&dir, index))?,
+                    data_points: fwmerge.data_points,
+                    low_confidence: fwmerge.low_confidence,
+                    hwdb: fwmerge.hwdb,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // The metadata of the results can change without the results
+        // changing, always load it.
+        let results = BenchMerge::result_paths_for(&database_directory(version, model_name))?
+            .iter()
+            .map(|path| ResultInfo::load(path))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Some(BenchMerge {
+            version: BenchVersion::new(version),
+            version_str: version.to_string(),
+            model_name: model_name.to_string(),
+            merged: ResultSet::load(version, &dir.join("merged-results.json.gz"))?,
+            data_points: cached.data_points,
+            low_confidence: cached.low_confidence,
+            fwmerges,
+            results,
+            hwdb: cached.hwdb,
+        }))
+    }
+
+    /// Saves `merge` and its pdf in `pdf_path` in the cache with `key`.
+    pub fn store(&self, merge: &BenchMerge, key: &str, pdf_path: &Path) -> Result<()> {
+        let dir = self.entry_dir(&merge.version_str, &merge.model_name);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+
+        merge.merged.save(&dir.join("merged-results.json.gz"))?;
+        for (index, fwmerge) in merge.fwmerges.iter().enumerate() {
+            fwmerge.merged.save(&Self::fwmerge_path(&dir, index))?;
+        }
+        fs::copy(pdf_path, dir.join("merged.pdf"))?;
+        let cached = CachedMerge {
+            data_points: merge.data_points,
+            low_confidence: merge.low_confidence.clone(),
+            hwdb: merge.hwdb.clone(),
+            fwmerges: merge
+                .fwmerges
+                .iter()
+                .map(|fwmerge| CachedFWMerge {
+                    fwrev: fwmerge.fwrev.clone(),
+                    data_points: fwmerge.data_points,
+                    low_confidence: fwmerge.low_confidence.clone(),
+                    hwdb: fwmerge.hwdb.clone(),
+                })
+                .collect(),
+        };
+        fs::write(dir.join("merge.json"), serde_json::to_string_pretty(&cached)?)?;
+
+        // Written last so that an interrupted store isn't used.
+        fs::write(dir.join("key"), key)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+use anyhow::Result;
+use clap::Parser;
+use glob::glob;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use iocost_benchmarks::common::{load_json, result_model_name, result_version, version_and_model};
+
+/// Kind of inconsistency found in the database
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ProblemKind {
+    /// The md5 of the result file doesn't match its name
+    Md5Mismatch,
+    /// The result file is in the directory of another model
+    ModelMismatch,
+    /// The result file is in the directory of another version
+    VersionMismatch,
+    /// The result file has no metadata file
+    MissingMetadata,
+    /// The metadata file can't be parsed or doesn't match its result
+    InvalidMetadata,
+    /// The metadata file has no result file
+    OrphanedMetadata,
+    /// The result file can't be loaded
+    LoadFailure,
+}
+
+/// An inconsistency found in the database
+#[derive(Debug, Serialize)]
+struct Problem {
+    path: PathBuf,
+    kind: ProblemKind,
+    detail: String,
+}
+
+/// Machine-readable result of the check, printed as JSON
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    /// Number of result files checked
+    results: usize,
+    /// Number of metadata files checked
+    metadata: usize,
+    problems: Vec<Problem>,
+}
+
+impl Summary {
+    fn add(&mut self, path: &Path, kind: ProblemKind, detail: String) {
+        eprintln!("{}: {:?}: {}", path.display(), kind, detail);
+        self.problems.push(Problem {
+            path: path.to_path_buf(),
+            kind,
+            detail,
+        });
+    }
+
+    /// Checks the result file in `path`, expected to be generated by
+    /// resctl-bench `version` (X.Y) on a `model_name` device.
+    fn check_result(&mut self, path: &Path, version: &str, model_name: &str) {
+        self.results += 1;
+
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) => return self.add(path, ProblemKind::LoadFailure, e.to_string()),
+        };
+        let md5 = format!("{:x}", md5::compute(&contents));
+        let filename = path.file_name().unwrap().to_string_lossy();
+        if filename != format!("result-{}.json.gz", md5) {
+            self.add(path, ProblemKind::Md5Mismatch, format!("md5 is {}", md5));
+        }
+
+        let metadata_path = metadata_path_for(path);
+        if metadata_path.exists() {
+            self.check_metadata(&metadata_path, version, model_name);
+        } else {
+            self.add(
+                path,
+                ProblemKind::MissingMetadata,
+                format!("{} not found", metadata_path.display()),
+            );
+        }
+
+        let result = match load_json(&path.to_string_lossy()) {
+            Ok(result) => result,
+            Err(e) => return self.add(path, ProblemKind::LoadFailure, format!("{:#}", e)),
+        };
+        match result_version(&result) {
+            Ok(v) if v == version => {}
+            Ok(v) => self.add(
+                path,
+                ProblemKind::VersionMismatch,
+                format!("generated by resctl-bench {}", v),
+            ),
+            Err(e) => self.add(path, ProblemKind::VersionMismatch, format!("{:#}", e)),
+        }
+        let result_model = result_model_name(&result);
+        if result_model != model_name {
+            self.add(
+                path,
+                ProblemKind::ModelMismatch,
+                format!("generated on {}", result_model),
+            );
+        }
+    }
+
+    /// Checks that the metadata file in `path` describes a result of
+    /// resctl-bench `version` (X.Y) on a `model_name` device.
+    fn check_metadata(&mut self, path: &Path, version: &str, model_name: &str) {
+        self.metadata += 1;
+
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => return self.add(path, ProblemKind::InvalidMetadata, e.to_string()),
+        };
+        let metadata = match json::parse(&contents) {
+            Ok(metadata) => metadata,
+            Err(e) => return self.add(path, ProblemKind::InvalidMetadata, e.to_string()),
+        };
+        if metadata["version"] != version {
+            self.add(
+                path,
+                ProblemKind::InvalidMetadata,
+                format!("version is {}", metadata["version"]),
+            );
+        }
+        if metadata["model_name"] != model_name {
+            self.add(
+                path,
+                ProblemKind::InvalidMetadata,
+                format!("model_name is {}", metadata["model_name"]),
+            );
+        }
+    }
+
+    /// Checks all the result and metadata files in `database_dir`.
+    fn check_database(&mut self, database_dir: &str) -> Result<()> {
+        // This expects the results to be laid out as
+        // <database_dir>/<version>/<model>/result-<md5>.json.gz, see
+        // merge-results.
+        for path in glob(&format!("{}/*/*/result-*.json.gz", database_dir))?.flatten() {
+            let (version, model_name) = version_and_model(&path);
+            self.check_result(&path, &version, &model_name);
+        }
+        for path in glob(&format!("{}/*/*/*.json.metadata", database_dir))?.flatten() {
+            let result_path = result_path_for(&path);
+            if !result_path.exists() {
+                self.metadata += 1;
+                self.add(
+                    &path,
+                    ProblemKind::OrphanedMetadata,
+                    format!("{} not found", result_path.display()),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the path of the metadata file of the result file in `path`
+fn metadata_path_for(path: &Path) -> PathBuf {
+    path.with_extension("").with_extension("json.metadata")
+}
+
+/// Returns the path of the result file of the metadata file in `path`
+fn result_path_for(path: &Path) -> PathBuf {
+    path.with_extension("").with_extension("json.gz")
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+/// Checks the consistency of the resctl-bench results database
+///
+/// Prints a JSON summary of the problems found and exits with a
+/// non-zero status if there's any.
+struct Cli {
+    /// Database dir to check
+    #[arg(short, long, value_name = "DIR", default_value = "database")]
+    database_dir: String,
+}
+
+fn main() -> Result<()> {
+    let args = Cli::parse();
+    let mut summary = Summary::default();
+    summary.check_database(&args.database_dir)?;
+
+    println!("{}", serde_json::to_string_pretty(&summary)?);
+    if !summary.problems.is_empty() {
+        exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Writes `contents` gzipped in `model_dir` under `dir`, named after
+    /// its md5, and returns its path.
+    fn write_gz(dir: &Path, model_dir: &str, contents: &[u8]) -> PathBuf {
+        let mut encoder = libflate::gzip::Encoder::new(vec![]).unwrap();
+        encoder.write_all(contents).unwrap();
+        let gz = encoder.finish().into_result().unwrap();
+        let path = dir
+            .join(model_dir)
+            .join(format!("result-{:x}.json.gz", md5::compute(&gz)));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, gz).unwrap();
+        path
+    }
+
+    /// Writes a result file of resctl-bench `bench_version` on a `model`
+    /// device in `model_dir` under `dir` and returns its path.
+    fn write_result(dir: &Path, model_dir: &str, bench_version: &str, model: &str) -> PathBuf {
+        let result = json::array![{
+            sysinfo: {
+                bench_version: bench_version,
+                sysreqs_report: { scr_dev_model: model },
+            },
+        }];
+        write_gz(dir, model_dir, result.dump().as_bytes())
+    }
+
+    /// Writes the metadata file of the result file in `path`.
+    fn write_metadata(path: &Path, version: &str, model_name: &str) {
+        let metadata = json::object! { version: version, model_name: model_name };
+        fs::write(metadata_path_for(path), metadata.dump()).unwrap();
+    }
+
+    #[test]
+    fn test_path_for() {
+        let result = Path::new("database/2.2/Model/result-0123.json.gz");
+        let metadata = Path::new("database/2.2/Model/result-0123.json.metadata");
+        assert_eq!(metadata_path_for(result), metadata);
+        assert_eq!(result_path_for(metadata), result);
+        assert_eq!(result_path_for(&metadata_path_for(result)), result);
+    }
+
+    #[test]
+    fn test_check_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path();
+        let model = "Fanxiang S500Pro 1TB";
+        let model_dir = "Fanxiang_S500Pro_1TB";
+
+        // Consistent result and metadata.
+        let good = write_result(db, &format!("2.2/{}", model_dir), "2.2.3 abc", model);
+        write_metadata(&good, "2.2", model_dir);
+
+        let missing = write_result(db, "2.2/Missing", "2.2.3 abc", "Missing");
+
+        let orphaned = db.join("2.2/Orphaned/result-0123.json.gz");
+        fs::create_dir_all(orphaned.parent().unwrap()).unwrap();
+        write_metadata(&orphaned, "2.2", "Orphaned");
+
+        let unreadable = write_gz(db, "2.2/Unreadable", b"not json");
+        write_metadata(&unreadable, "2.2", "Unreadable");
+
+        let invalid = write_result(db, "2.2/Invalid", "2.2.3 abc", "Invalid");
+        write_metadata(&invalid, "2.1", "Invalid");
+
+        let version = write_result(db, "2.2/Version", "2.1.0 abc", "Version");
+        write_metadata(&version, "2.2", "Version");
+
+        let other_model = write_result(db, "2.2/Other", "2.2.3 abc", model);
+        write_metadata(&other_model, "2.2", "Other");
+
+        let renamed = db.join("2.2/Renamed/result-0123.json.gz");
+        fs::create_dir_all(renamed.parent().unwrap()).unwrap();
+        fs::copy(&missing, &renamed).unwrap();
+        write_metadata(&renamed, "2.2", "Renamed");
+
+        let mut summary = Summary::default();
+        summary.check_database(&db.to_string_lossy()).unwrap();
+        let mut problems: Vec<(PathBuf, String)> = summary
+            .problems
+            .iter()
+            .map(|p| (p.path.clone(), format!("{:?}", p.kind)))
+            .collect();
+        problems.sort();
+
+        let mut expected: Vec<(PathBuf, String)> = [
+            (missing, "MissingMetadata"),
+            (metadata_path_for(&orphaned), "OrphanedMetadata"),
+            (unreadable, "LoadFailure"),
+            (metadata_path_for(&invalid), "InvalidMetadata"),
+            (version, "VersionMismatch"),
+            (other_model, "ModelMismatch"),
+            (renamed.clone(), "Md5Mismatch"),
+            (renamed, "ModelMismatch"),
+        ]
+        .into_iter()
+        .map(|(path, kind)| (path, kind.to_string()))
+        .collect();
+        expected.sort();
+
+        assert_eq!(problems, expected);
+        assert_eq!(summary.results, 7);
+        assert_eq!(summary.metadata, 7);
+    }
+}
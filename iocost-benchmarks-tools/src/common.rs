@@ -1,144 +1,541 @@
 use anyhow::{anyhow, bail, Result, Context};
 use glob::glob;
 use json::JsonValue;
-use semver::{Version, VersionReq};
+use resctl_bench::{model_name, JobCtxs};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
 use std::collections::HashMap;
 use std::fs;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use regex::Regex;
+use std::sync::OnceLock;
+
+use crate::fwrev::{sort_fwrevs, FwrevOrder};
+use crate::quality::QualityPolicy;
 
 const MINIMUM_DATA_POINTS: usize = 4;
 const MINIMUM_DIFFERENT_RESULTS: u64 = 1;
 
-#[derive(Debug, Eq, Hash, PartialEq)]
-pub enum MajorMinor {
-    V2_1,
-    V2_2,
+/// Top-level struct to parse the config toml file
+#[derive(Debug, Default, Deserialize)]
+pub struct TomlData {
+    #[serde(default)]
+    pub config: Config,
+    /// resctl-bench version registry, keyed by major.minor
+    #[serde(default)]
+    pub versions: HashMap<String, VersionConfig>,
+    #[serde(default)]
+    pub policy: Policy,
+    #[serde(default)]
+    pub quality: QualityPolicy,
+}
+
+/// Struct to parse the [config] section of the config toml file
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    pub database_dir: Option<String>,
 }
 
-/// Models resctl-bench version info as a `MajorMinor` enum and as a
-/// full `semver` read from the output of resctl-bench --version
+/// Struct to parse the [policy] section of the config toml file, which
+/// controls which merge of each model goes into the final hwdb file.
+/// By default, the merge with the most data points is selected.
+#[derive(Debug, Default, Deserialize)]
+pub struct Policy {
+    /// Select the newest resctl-bench version instead of the one with
+    /// the most data points
+    #[serde(default)]
+    pub prefer_newest: bool,
+    /// Merges with fewer data points are never selected
+    #[serde(default)]
+    pub min_data_points: usize,
+    /// resctl-bench versions (X.Y) which are never selected
+    #[serde(default)]
+    pub exclude_versions: Vec<String>,
+    /// Firmware revisions which don't get fwrev-specific solutions
+    #[serde(default)]
+    pub exclude_fwrevs: Vec<String>,
+    /// How firmware revisions are ordered to find the latest one
+    #[serde(default)]
+    pub fwrev_order: FwrevOrder,
+    /// Generate fwrev-specific solutions for every firmware revision
+    /// with enough data points instead of only the latest one
+    #[serde(default)]
+    pub all_fwrevs: bool,
+    /// Per-model overrides, keyed by model name
+    #[serde(default)]
+    pub models: HashMap<String, ModelPolicy>,
+}
+
+/// Struct to parse a [policy.models."MODEL"] section of the config
+/// toml file. Exclusions are added to the global ones.
+#[derive(Debug, Default, Deserialize)]
+pub struct ModelPolicy {
+    /// resctl-bench version (X.Y) to select regardless of the rest of
+    /// the policy. Pinning an excluded version is an error.
+    pub pin: Option<String>,
+    pub prefer_newest: Option<bool>,
+    pub min_data_points: Option<usize>,
+    #[serde(default)]
+    pub exclude_versions: Vec<String>,
+    #[serde(default)]
+    pub exclude_fwrevs: Vec<String>,
+    pub fwrev_order: Option<FwrevOrder>,
+    pub all_fwrevs: Option<bool>,
+}
+
+/// How the fwrev-specific merges of a model are done
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FWMergeOpts {
+    pub order: FwrevOrder,
+    pub all: bool,
+}
+
+impl Policy {
+    /// Returns the fwrev-specific merge options for `model`.
+    pub fn fwmerge_opts(&self, model: &str) -> FWMergeOpts {
+        let model_policy = self.models.get(model);
+        FWMergeOpts {
+            order: model_policy
+                .and_then(|p| p.fwrev_order)
+                .unwrap_or(self.fwrev_order),
+            all: model_policy
+                .and_then(|p| p.all_fwrevs)
+                .unwrap_or(self.all_fwrevs),
+        }
+    }
+}
+
+/// Struct to parse a [versions."X.Y"] section of the config toml file.
+/// Capabilities which aren't specified default to `true` for the
+/// version of the linked resctl-bench and to `false` otherwise.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct VersionConfig {
+    /// Path of the resctl-bench binary processing results of this
+    /// version. Defaults to ./resctl-demo-vX.Y/resctl-bench.
+    pub resctl_bench: Option<PathBuf>,
+    /// `format iocost-tune:hwdb` is supported
+    pub hwdb: Option<bool>,
+    /// `format iocost-tune:high-level` is supported
+    pub high_level: Option<bool>,
+    /// `format iocost-tune:hwdb-fwrev` is supported
+    pub hwdb_fwrev: Option<bool>,
+}
+
+static VERSION_REGISTRY: OnceLock<HashMap<String, VersionConfig>> = OnceLock::new();
+
+/// Sets the resctl-bench version registry, usually the [versions]
+/// table of the config toml file. Can only be called once, before any
+/// `BenchVersion` is created.
+pub fn set_version_registry(versions: HashMap<String, VersionConfig>) {
+    VERSION_REGISTRY
+        .set(versions)
+        .expect("Version registry already set");
+}
+
+/// Loads the config toml file in `path`
+pub fn load_config(path: &str) -> Result<TomlData> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Can't open config file: {}", path))?;
+    toml::from_str(&contents).with_context(|| format!("Error parsing toml file {}", path))
+}
+
+/// Models a resctl-bench version (X.Y), the binary processing its
+/// results and what the binary can do.
 #[derive(Debug, Eq, Hash, PartialEq)]
 pub struct BenchVersion {
-    pub major_minor: MajorMinor,
-    pub semver: Version,
+    pub major_minor: String,
+    /// Results are processed by the linked resctl-bench
+    pub native: bool,
+    /// Binary used for results which aren't processed natively
+    pub resctl_bench: PathBuf,
+    pub hwdb: bool,
+    pub high_level: bool,
+    pub hwdb_fwrev: bool,
 }
 
 impl BenchVersion {
-    /// Creates a BenchVersion from a `version` string (X.Y), runs
-    /// `resctl-bench --version` on the appropriate binary version and
-    /// parses the output to get the full version string.
+    /// Creates a BenchVersion from a `version` string (X.Y) using the
+    /// version registry. The version of the linked resctl-bench is
+    /// handled natively unless the registry sets a binary for it.
     pub fn new(version: &str) -> Self {
-        let major_minor = match version {
-            "2.1" => MajorMinor::V2_1,
-            "2.2" => MajorMinor::V2_2,
-            _ => unimplemented!(),
-        };
-        let version_str = run_resctl(version, &["--version"])
-            .expect("Could not run resctl-bench to get version");
-        let re = Regex::new(r"resctl-bench (?<version>\d+\.\d+\.\d+)[^\s]?")
-            .unwrap();
-        let caps = re.captures(&version_str)
-            .expect(&format!("Error parsing resctl-bench --version: {}", version_str));
-        let semver = Version::parse(caps.name("version").unwrap().as_str())
-            .expect("Failed to parse version with semver");
+        let config = VERSION_REGISTRY
+            .get()
+            .and_then(|versions| versions.get(version))
+            .cloned()
+            .unwrap_or_default();
+        let native = config.resctl_bench.is_none() && is_native_version(version);
         BenchVersion {
-            major_minor,
-            semver,
+            major_minor: version.to_string(),
+            native,
+            resctl_bench: config.resctl_bench.unwrap_or_else(|| {
+                PathBuf::from(format!("./resctl-demo-v{}/resctl-bench", version))
+            }),
+            hwdb: config.hwdb.unwrap_or(native),
+            high_level: config.high_level.unwrap_or(native),
+            hwdb_fwrev: config.hwdb_fwrev.unwrap_or(native),
         }
     }
 }
 
-#[derive(Debug, Eq, Hash, PartialEq)]
+/// A set of resctl-bench results. Versions supported by the linked
+/// resctl-bench are processed in-process, others are kept in a file
+/// which is processed by running an external resctl-bench binary.
+#[derive(Debug)]
+pub enum ResultSet {
+    Native(JobCtxs),
+    External { version: String, path: PathBuf },
+}
+
+impl ResultSet {
+    /// Loads the result file in `path` generated by resctl-bench
+    /// `version` (X.Y).
+    pub fn load(version: &str, path: &Path) -> Result<Self> {
+        if BenchVersion::new(version).native {
+            resctl_bench::init_lib();
+            let jobs = JobCtxs::load_results(&path.to_string_lossy())
+                .with_context(|| format!("Failed to load {:?}", path))?;
+            Ok(ResultSet::Native(jobs))
+        } else {
+            Ok(ResultSet::External {
+                version: version.to_string(),
+                path: path.to_path_buf(),
+            })
+        }
+    }
+
+    /// Merges the result files in `srcs` generated by resctl-bench
+    /// `version` (X.Y). The merged result is kept in memory if the
+    /// version is handled natively, otherwise it's written to
+    /// `output_path` by the external resctl-bench binary. `bootstrap` is
+    /// the number of resamples used to determine the confidence of the
+    /// iocost-tune solutions, only supported natively.
+    pub fn merge(
+        version: &str,
+        srcs: &[PathBuf],
+        output_path: &Path,
+        bootstrap: u32,
+    ) -> Result<Self> {
+        let srcs: Vec<String> = srcs
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect();
+
+        if BenchVersion::new(version).native {
+            resctl_bench::init_lib();
+            println!("Merging results natively: {}", srcs.join(" "));
+            let args = resctl_bench_intf::Args {
+                merge_srcs: srcs,
+                merge_bootstrap: bootstrap,
+                ..Default::default()
+            };
+            let merged = ResultSet::Native(resctl_bench::merge_jobs(&args)?);
+            println!("{}", merged.format("merge-info")?);
+            return Ok(merged);
+        }
+
+        if let Some(dir) = output_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut arguments = vec![
+            "--result".to_string(),
+            output_path.to_string_lossy().to_string(),
+            "merge".to_string(),
+        ];
+        arguments.extend(srcs);
+
+        let mut output = format!("Merging results with: {}\n", arguments.join(" "));
+        output.push_str(&run_resctl(version, arguments.as_slice())?);
+        println!("{}", output);
+
+        Ok(ResultSet::External {
+            version: version.to_string(),
+            path: output_path.to_path_buf(),
+        })
+    }
+
+    /// Formats the results according to the job spec `spec` (e.g.
+    /// "iocost-tune:hwdb") and returns the output, same as
+    /// `resctl-bench --result <file> format <spec>`.
+    pub fn format(&self, spec: &str) -> Result<String> {
+        match self {
+            ResultSet::Native(jobs) => {
+                let spec = resctl_bench_intf::Args::parse_job_spec(spec)?;
+                jobs.format_matching(&spec, "")
+            }
+            ResultSet::External { version, path } => run_resctl(
+                version,
+                &["--result", &path.to_string_lossy(), "format", spec],
+            ),
+        }
+    }
+
+    /// Returns the iocost-tune result, e.g. `result["solutions"]` holds
+    /// the model and QoS parameters of each solution.
+    pub fn tune_result(&self) -> Result<JsonValue> {
+        match self {
+            ResultSet::Native(jobs) => {
+                let result = jobs
+                    .vec
+                    .iter()
+                    .find(|jctx| jctx.data.spec.kind == "iocost-tune")
+                    .and_then(|jctx| jctx.data.result.as_ref())
+                    .ok_or_else(|| anyhow!("Could not find iocost-tune result in merge"))?;
+                Ok(json::parse(&serde_json::to_string(result)?)?)
+            }
+            ResultSet::External { path, .. } => {
+                let mut result = load_json(&path.to_string_lossy())?;
+                let job = result
+                    .members_mut()
+                    .find(|v| v["spec"]["kind"] == "iocost-tune")
+                    .ok_or_else(|| anyhow!("Could not find iocost-tune result in {:?}", path))?;
+                Ok(job["result"].take())
+            }
+        }
+    }
+
+    /// Returns the number of data points in the iocost-tune result,
+    /// including outliers.
+    pub fn data_points(&self) -> Result<usize> {
+        // TODO: we probably want to move this processing to resctl-bench format output.
+        let result = self.tune_result()?;
+        Ok(result["data"]["MOF"]["data"].len() + result["data"]["MOF"]["outliers"].len())
+    }
+
+    /// Returns the names of the iocost-tune solutions which resctl-bench
+    /// flagged as low confidence. Empty if the version doesn't report
+    /// confidence.
+    pub fn low_confidence(&self) -> Result<Vec<String>> {
+        Ok(self.tune_result()?["confidence"]
+            .entries()
+            .filter(|(_, conf)| conf["low"].as_bool() == Some(true))
+            .map(|(name, _)| name.to_string())
+            .collect())
+    }
+
+    /// Saves the results in `path`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        match self {
+            ResultSet::Native(jobs) => jobs.save_results(&path.to_string_lossy()),
+            ResultSet::External { path: src, .. } => {
+                fs::copy(src, path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the iocost-tune result as a pdf file in `pdf_path`.
+    pub fn save_pdf(&self, pdf_path: &Path) -> Result<()> {
+        println!("PDF Path: {:#?}\n", pdf_path);
+        self.format(&format!("iocost-tune:pdf={}", pdf_path.to_string_lossy()))
+            .map(|_| ())
+    }
+
+    /// Removes the merged result file, if any.
+    fn discard(self) -> Result<()> {
+        if let ResultSet::External { path, .. } = self {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Summary of a result file in the database and its metadata
+#[skip_serializing_none]
+#[derive(Clone, Debug, Serialize)]
+pub struct ResultInfo {
+    /// Path of the result file
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// Name of the result file
+    pub file: String,
+    pub fwrev: String,
+    pub kernel_version: String,
+    /// When the benchmark finished, in RFC 3339 format
+    pub date: String,
+    /// Github issue the result was submitted in, if known
+    pub issue: Option<u64>,
+    /// Result file url, if known
+    pub url: Option<String>,
+}
+
+impl ResultInfo {
+    /// Loads the summary of the result file in `path` and of its
+    /// metadata file, if any.
+    pub fn load(path: &Path) -> Result<Self> {
+        let result = load_json(&path.to_string_lossy())
+            .with_context(|| format!("Failed to load {:?}", path))?;
+        Ok(Self::from_result(path, &result))
+    }
+
+    /// Same as `load()` with `result`, the already loaded contents of the
+    /// result file in `path`.
+    pub fn from_result(path: &Path, result: &JsonValue) -> Self {
+        let report = &result[0]["sysinfo"]["sysreqs_report"];
+        let end = result
+            .members()
+            .filter_map(|job| job["period"][1].as_i64())
+            .max()
+            .unwrap_or_default();
+        let date = chrono::DateTime::from_timestamp(end, 0)
+            .map(|date| date.to_rfc3339())
+            .unwrap_or_default();
+
+        let metadata = fs::read_to_string(path.with_extension("").with_extension("json.metadata"))
+            .ok()
+            .and_then(|metadata| json::parse(&metadata).ok())
+            .unwrap_or(JsonValue::Null);
+
+        ResultInfo {
+            path: path.to_path_buf(),
+            file: path.file_name().unwrap().to_string_lossy().to_string(),
+            fwrev: report["scr_dev_fwrev"].to_string(),
+            kernel_version: report["kernel_version"].to_string(),
+            date,
+            issue: metadata["issue"].as_u64(),
+            url: metadata["url"].as_str().map(str::to_string),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct BenchMerge {
     pub version: BenchVersion,
     pub version_str: String,
     pub model_name: String,
-    pub path: PathBuf,
+    pub merged: ResultSet,
     pub data_points: usize,
-    pub fwmerge: Option<BenchFWMerge>,
+    /// Solutions flagged as low confidence
+    pub low_confidence: Vec<String>,
+    /// fwrev-specific merges, the latest fwrev first
+    pub fwmerges: Vec<BenchFWMerge>,
+    /// The merged result files
+    pub results: Vec<ResultInfo>,
+    /// hwdb solutions, if supported by the resctl-bench version
+    pub hwdb: Option<String>,
 }
 
-#[derive(Debug, Eq, Hash, PartialEq)]
+#[derive(Debug)]
 pub struct BenchFWMerge {
     pub fwrev: String,
-    pub path: PathBuf,
+    pub merged: ResultSet,
     pub data_points: usize,
+    /// Solutions flagged as low confidence
+    pub low_confidence: Vec<String>,
+    /// fwrev-specific hwdb solutions
+    pub hwdb: String,
 }
 
-#[allow(dead_code)]
 impl BenchMerge {
-    pub fn merge(version: String, model_name: String) -> Result<Self> {
+    pub fn merge(
+        version: String,
+        model_name: String,
+        fwmerge_opts: &FWMergeOpts,
+        bootstrap: u32,
+    ) -> Result<Self> {
         let directory = database_directory(&version, &model_name);
         let output_path = merged_file(&version, &model_name, None);
 
-        Self::do_merge(&version, &directory, &output_path)?;
+        let merged = Self::merge_directory(&version, &directory, &output_path, bootstrap)?;
+
+        let data_points = merged.data_points()?;
+        let low_confidence = merged.low_confidence()?;
 
-        let data_points = Self::get_data_points(&output_path)?;
+        let results = Self::result_paths_for(&directory)?
+            .iter()
+            .map(|path| ResultInfo::load(path))
+            .collect::<Result<Vec<_>>>()?;
 
-        let fwmerge = Self::try_fwmerge(data_points, &version, &model_name, &directory)?;
+        let bench_version = BenchVersion::new(&version);
+        let fwmerges = if bench_version.hwdb_fwrev {
+            Self::fwmerges(
+                data_points,
+                &version,
+                &model_name,
+                &results,
+                fwmerge_opts,
+                bootstrap,
+            )?
+        } else {
+            vec![]
+        };
+        let hwdb = if bench_version.hwdb {
+            Some(merged.format("iocost-tune:hwdb")?)
+        } else {
+            None
+        };
 
         Ok(BenchMerge {
-            version: BenchVersion::new(&version),
+            version: bench_version,
             version_str: version,
             model_name,
-            path: output_path,
+            merged,
             data_points,
-            fwmerge,
+            low_confidence,
+            fwmerges,
+            results,
+            hwdb,
         })
     }
 
-    fn try_fwmerge(
+    /// Merges the results of the latest fwrev, or of every fwrev if
+    /// `opts.all` is set, and returns the merges which differ enough from
+    /// the generic one to get specific solutions, the latest fwrev first.
+    fn fwmerges(
         common_data_points: usize,
         version: &str,
         model_name: &str,
-        directory: &Path,
-    ) -> Result<Option<BenchFWMerge>> {
-        let results = Self::result_paths_for(directory)?;
-
+        results: &[ResultInfo],
+        opts: &FWMergeOpts,
+        bootstrap: u32,
+    ) -> Result<Vec<BenchFWMerge>> {
         let mut fwrev_map: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        for r in results {
+            fwrev_map.entry(r.fwrev.clone()).or_default().push(r.path.clone());
+        }
 
-        // This uses alphabetical sorting to determine the latest firmware revision.
-        // Based on how fwupd compares versions for NVME devices it should be good
-        // enough, as it uses the PLAIN format for version numbers of NVME devices,
-        // and does a simple g_strcmp0() for those.
-        let max_fwrev = results
-            .iter()
-            .map(|r| {
-                let json = &load_json(&r.to_string_lossy()).expect("Failed to load result")[0];
-                let fwrev = json["sysinfo"]["sysreqs_report"]["scr_dev_fwrev"].to_string();
-
-                fwrev_map.entry(fwrev.clone()).or_default().push(r.clone());
-
-                fwrev
-            })
-            .max_by(|a, b| a.cmp(b))
-            .unwrap();
-
-        let output_path = merged_file(version, model_name,
-            Some(max_fwrev.as_str()));
-        let mut arguments = vec![
-            "--result".to_string(),
-            output_path.to_string_lossy().to_string(),
-            "merge".to_string(),
-        ];
-        arguments.extend(
-            fwrev_map
-                .get(&max_fwrev)
-                .unwrap()
-                .iter()
-                .map(|p| p.to_string_lossy().to_string()),
+        let mut fwrevs: Vec<String> = fwrev_map.keys().cloned().collect();
+        let scheme = sort_fwrevs(opts.order, model_name, &mut fwrevs);
+        fwrevs.reverse();
+        println!(
+            "Model {} fwrevs ordered by {} scheme, latest first: {}",
+            model_name,
+            scheme,
+            fwrevs.join(" ")
         );
+        if !opts.all {
+            fwrevs.truncate(1);
+        }
 
-        let mut output = format!(
-            "Merging FW-specific results with: {}\n",
-            arguments.join(" ")
-        );
-        output.push_str(&run_resctl(version, arguments.as_slice())?);
-        println!("{}", output);
+        let mut fwmerges = vec![];
+        for fwrev in fwrevs {
+            if let Some(fwmerge) = Self::try_fwmerge(
+                common_data_points,
+                version,
+                model_name,
+                fwrev,
+                &fwrev_map,
+                bootstrap,
+            )? {
+                fwmerges.push(fwmerge);
+            }
+        }
+        Ok(fwmerges)
+    }
+
+    fn try_fwmerge(
+        common_data_points: usize,
+        version: &str,
+        model_name: &str,
+        fwrev: String,
+        fwrev_map: &HashMap<String, Vec<PathBuf>>,
+        bootstrap: u32,
+    ) -> Result<Option<BenchFWMerge>> {
+        let output_path = merged_file(version, model_name, Some(fwrev.as_str()));
+        println!("Merging FW-specific results for fwrev {}", fwrev);
+        let merged = ResultSet::merge(version, &fwrev_map[&fwrev], &output_path, bootstrap)?;
 
-        let data_points = Self::get_data_points(&output_path)?;
+        let data_points = merged.data_points()?;
         // If there are almost the same number of results for the
         // generic merge as there are for the specific fwrev, just use
         // the generic one.
@@ -148,62 +545,45 @@ impl BenchMerge {
         {
             println!(
                 "Model {} fwrev {} has enough data points: {}, generating specific solution.",
-                model_name, max_fwrev, data_points
+                model_name, fwrev, data_points
             );
+            let hwdb = merged.format("iocost-tune:hwdb-fwrev")?;
+            let low_confidence = merged.low_confidence()?;
             return Ok(Some(BenchFWMerge {
-                fwrev: max_fwrev,
-                path: output_path,
+                fwrev,
+                merged,
                 data_points,
+                low_confidence,
+                hwdb,
             }));
         }
 
         if data_points < MINIMUM_DATA_POINTS {
             println!(
                 "Model {} fwrev {} has too few data points: {}, no specific solution generated.",
-                model_name, max_fwrev, data_points
+                model_name, fwrev, data_points
             );
         } else {
-            println!("Model {} fwrev {} has almost the same input as the generic one, no specific solution generated.", model_name, max_fwrev);
+            println!("Model {} fwrev {} has almost the same input as the generic one, no specific solution generated.", model_name, fwrev);
         }
 
-        std::fs::remove_file(output_path)?;
+        merged.discard()?;
         Ok(None)
     }
 
-    pub fn do_merge(version: &str, directory: &Path, output_path: &Path) -> Result<()> {
-        let results = Self::result_paths_for(directory)?
-            .into_iter()
-            .map(|p| p.to_string_lossy().to_string());
-
-        let mut arguments = vec![
-            "--result".to_string(),
-            output_path.to_string_lossy().to_string(),
-            "merge".to_string(),
-        ];
-        arguments.extend(results);
-
-        let mut output = format!("Merging results with: {}\n", arguments.join(" "));
-        output.push_str(&run_resctl(version, arguments.as_slice())?);
-        println!("{}", output);
-
-        Ok(())
-    }
-
-    fn get_data_points(path: &Path) -> Result<usize> {
-        // TODO: we probably want to move this processing to resctl-bench format output.
-        let result = load_json(&path.to_string_lossy())?;
-        let result = result
-            .members()
-            .find(|v| v["spec"]["kind"] == "iocost-tune")
-            .expect("Could not find iocost-tune spec in merge file");
-
-        Ok(result["result"]["data"]["MOF"]["data"].members().count()
-            + result["result"]["data"]["MOF"]["outliers"]
-                .members()
-                .count())
+    /// Merges all the results in `directory`. `output_path` is only
+    /// written if the merge is done by an external resctl-bench binary.
+    pub fn merge_directory(
+        version: &str,
+        directory: &Path,
+        output_path: &Path,
+        bootstrap: u32,
+    ) -> Result<ResultSet> {
+        let results = Self::result_paths_for(directory)?;
+        ResultSet::merge(version, &results, output_path, bootstrap)
     }
 
-    fn result_paths_for(directory: &Path) -> Result<Vec<PathBuf>> {
+    pub fn result_paths_for(directory: &Path) -> Result<Vec<PathBuf>> {
         Ok(
             glob(&format!("{}/*.json.gz", directory.to_string_lossy()))
                 .unwrap()
@@ -213,21 +593,18 @@ impl BenchMerge {
     }
 
     pub fn save_pdf_in(&self, target_dir: &Path) -> Result<()> {
+        fs::create_dir_all(target_dir)?;
         let filename = self.build_descriptive_filename("pdf", None);
-        save_pdf_to(&self.version_str, &self.path, target_dir, filename)
+        self.merged.save_pdf(&target_dir.join(filename))
     }
 
     pub fn create_hwdb_in(&self, target_dir: &Path) -> Result<()> {
         fs::create_dir_all(target_dir).expect("Could not create the target hwdb directory");
 
-        // The hwdb subcommand was introduced in resctl-bench v2.2.4.
-        if VersionReq::parse("<2.2.4")
-            .unwrap()
-            .matches(&self.version.semver)
-        {
+        if self.hwdb.is_none() {
             println!(
-                "Skipping hwdb generation as this version of resctl-bench does not have hwdb support: {}",
-                self.version.semver
+                "Skipping hwdb generation as resctl-bench {} does not have hwdb support",
+                self.version.major_minor
             );
             return Ok(());
         }
@@ -236,35 +613,30 @@ impl BenchMerge {
 
         let mut file = fs::File::create(target_dir.join(filename))?;
 
-        let output = run_resctl(
-            &self.version_str,
-            &[
-                "--result",
-                &self.path.to_string_lossy(),
-                "format",
-                "iocost-tune:hwdb",
-            ],
-        )?;
-
-        write!(file, "{}", output)?;
-
-        if let Some(fwmerge) = &self.fwmerge {
-            let output = run_resctl(
-                &self.version_str,
-                &[
-                    "--result",
-                    &fwmerge.path.to_string_lossy(),
-                    "format",
-                    "iocost-tune:hwdb-fwrev",
-                ],
-            )?;
-
-            write!(file, "\n{}", output)?;
-        }
+        write!(file, "{}", self.format_hwdb(|_| true)?)?;
 
         Ok(())
     }
 
+    /// Returns the hwdb solutions of the merge, followed by the
+    /// fwrev-specific ones of the fwrevs `with_fwrev` returns true for.
+    pub fn format_hwdb<F: Fn(&str) -> bool>(&self, with_fwrev: F) -> Result<String> {
+        let mut output = match &self.hwdb {
+            Some(hwdb) => hwdb.clone(),
+            None => bail!(
+                "resctl-bench {} does not have hwdb support",
+                self.version.major_minor
+            ),
+        };
+
+        for fwmerge in self.fwmerges.iter().filter(|f| with_fwrev(&f.fwrev)) {
+            output.push('\n');
+            output.push_str(&fwmerge.hwdb);
+        }
+
+        Ok(output)
+    }
+
     pub fn build_descriptive_filename<'a, D: Into<Option<&'a str>>>(
         &self,
         extension: &str,
@@ -276,7 +648,7 @@ impl BenchMerge {
             format!(".{}", extension)
         };
 
-        let date = chrono::offset::Utc::now().format("%Y_%m_%d");
+        let date = source_date().format("%Y_%m_%d");
 
         let detail = match detail.into() {
             Some(d) => format!("{}-", d),
@@ -314,23 +686,12 @@ pub fn save_pdf_to(
         }
     };
 
-    println!("PDF Path: {:#?}\n", pdf_path);
-    run_resctl(
-        version,
-        &[
-            "--result",
-            &result.to_string_lossy(),
-            "format",
-            &format!("iocost-tune:pdf={}", pdf_path.to_string_lossy()),
-        ],
-    )
-    .map(|_| ())
+    ResultSet::load(version, result)?.save_pdf(&pdf_path)
 }
 
 /// Reads a gzipped json file, parses it and returns its contents
-#[allow(dead_code)]
 pub fn load_json(filename: &str) -> Result<JsonValue> {
-    let f = std::fs::File::open(&filename)?;
+    let f = std::fs::File::open(filename)?;
 
     let mut buf = vec![];
     libflate::gzip::Decoder::new(f)?.read_to_end(&mut buf)?;
@@ -338,23 +699,197 @@ pub fn load_json(filename: &str) -> Result<JsonValue> {
     Ok(json::parse(&String::from_utf8(buf)?)?)
 }
 
-/// Runs a specific `version` of resctl-bench with a list of arguments
-/// (`args`) and returns its output.
+/// Returns the (major, minor) numbers of a resctl-bench version (X.Y)
+/// for ordering.
+pub fn version_key(version: &str) -> (u64, u64) {
+    let mut numbers = version.split('.').map(|n| n.parse().unwrap_or(0));
+    (numbers.next().unwrap_or(0), numbers.next().unwrap_or(0))
+}
+
+/// Returns the (version, model name) of the database directory of the
+/// file in `path`.
+pub fn version_and_model(path: &Path) -> (String, String) {
+    let name = |p: Option<&Path>| {
+        p.and_then(|p| p.file_name())
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default()
+    };
+    let model_dir = path.parent();
+    (name(model_dir.and_then(|p| p.parent())), name(model_dir))
+}
+
+/// Returns the resctl-bench version (X.Y) which generated `result`, the
+/// contents of a result file.
+pub fn result_version(result: &JsonValue) -> Result<String> {
+    let full_version = result[0]["sysinfo"]["bench_version"].to_string();
+    let full_version = full_version.split_whitespace().next().unwrap_or_default();
+    let v = Version::parse(full_version)
+        .with_context(|| format!("Invalid bench_version {:?}", full_version))?;
+    Ok(format!("{}.{}", v.major, v.minor))
+}
+
+/// Returns the database directory name for the model of the device
+/// `result`, the contents of a result file, was generated on. See
+/// resctl_bench::model_name for the normalization.
+pub fn result_model_name(result: &JsonValue) -> String {
+    model_name::dir_name(&result[0]["sysinfo"]["sysreqs_report"]["scr_dev_model"].to_string())
+}
+
+/// Lowercase model name prefixes of the vendors whose model names don't
+/// start with the vendor name, checked in order. Other models are
+/// attributed to the first word of their name.
+const VENDOR_PREFIXES: &[(&str, &str)] = &[
+    ("samsung", "Samsung"),
+    ("mz", "Samsung"),
+    ("wdc", "Western Digital"),
+    ("wd", "Western Digital"),
+    ("intel", "Intel"),
+    ("kingston", "Kingston"),
+    ("ct", "Crucial"),
+    ("mtfd", "Micron"),
+    ("hfs", "SK hynix"),
+    ("shgp", "SK hynix"),
+    ("skhynix", "SK hynix"),
+    ("kxg", "Kioxia"),
+];
+
+/// Returns the vendor of the device `model`, as reported in
+/// sysreqs_report.scr_dev_model.
+pub fn model_vendor(model: &str) -> String {
+    let model = model_name::normalize(model);
+    let lower = model.to_lowercase();
+    for (prefix, vendor) in VENDOR_PREFIXES {
+        if lower.starts_with(prefix) {
+            return vendor.to_string();
+        }
+    }
+    model
+        .split(|c: char| c.is_whitespace() || c == '_')
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Returns a fingerprint of `result`, the contents of a result file,
+/// which doesn't depend on how the file was compressed or serialized:
+/// the md5 of the canonical form of the kind, period and sysinfo of
+/// every job and of the iocost-tune record.
+pub fn result_fingerprint(result: &JsonValue) -> String {
+    let mut canonical = String::new();
+    for job in result.members() {
+        let mut key = json::object! {
+            kind: job["spec"]["kind"].clone(),
+            period: job["period"].clone(),
+            sysinfo: job["sysinfo"].clone(),
+        };
+        if job["spec"]["kind"] == "iocost-tune" {
+            key["record"] = job["record"].clone();
+        }
+        write_canonical_json(&key, &mut canonical);
+    }
+    format!("{:x}", md5::compute(canonical))
+}
+
+/// Serializes `value` into `out` with the object keys sorted.
+fn write_canonical_json(value: &JsonValue, out: &mut String) {
+    match value {
+        JsonValue::Object(object) => {
+            let mut entries: Vec<_> = object.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&json::stringify(key));
+                out.push(':');
+                write_canonical_json(value, out);
+            }
+            out.push('}');
+        }
+        JsonValue::Array(array) => {
+            out.push('[');
+            for (i, value) in array.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical_json(value, out);
+            }
+            out.push(']');
+        }
+        _ => out.push_str(&value.dump()),
+    }
+}
+
+/// Returns the date of the database being processed, so that the
+/// output only depends on the input: SOURCE_DATE_EPOCH if set and
+/// valid, otherwise the date of the HEAD commit of the git repository
+/// containing the current directory, otherwise the current date.
+pub fn source_date() -> chrono::DateTime<chrono::Utc> {
+    static SOURCE_DATE: OnceLock<chrono::DateTime<chrono::Utc>> = OnceLock::new();
+    *SOURCE_DATE.get_or_init(|| {
+        let from_env = std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|epoch| {
+            let date = epoch
+                .parse::<i64>()
+                .ok()
+                .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0));
+            if date.is_none() {
+                println!("Warning: ignoring invalid SOURCE_DATE_EPOCH={:?}", epoch);
+            }
+            date
+        });
+        from_env
+            .or_else(|| {
+                git2::Repository::discover(".")
+                    .and_then(|repo| Ok(repo.head()?.peel_to_commit()?.time().seconds()))
+                    .ok()
+                    .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+            })
+            .unwrap_or_else(chrono::Utc::now)
+    })
+}
+
+/// Returns the version of the resctl-bench linked into this binary.
+pub fn native_version() -> Version {
+    Version::parse(&resctl_bench::VERSION).expect("Failed to parse resctl-bench version")
+}
+
+/// Returns `true` if `version` (X.Y) is the version of the linked
+/// resctl-bench.
+fn is_native_version(version: &str) -> bool {
+    let native = native_version();
+    version == format!("{}.{}", native.major, native.minor)
+}
+
+/// Runs the resctl-bench binary registered for `version` with a list
+/// of arguments (`args`) and returns its output.
 pub fn run_resctl<S: AsRef<std::ffi::OsStr>>(version: &str, args: &[S]) -> Result<String> {
-    let bench_path = format!("./resctl-demo-v{}/resctl-bench", version);
+    let bench_path = BenchVersion::new(version).resctl_bench;
+    if !bench_path.exists() {
+        bail!(
+            "No resctl-bench binary for version {} at {:?}, set it in the [versions.\"{}\"] table of the config file",
+            version, bench_path, version
+        );
+    }
+    let bench_path = bench_path.to_string_lossy();
     // args as string for error message
     let args_string = args
         .iter()
         .map(|s| s.as_ref().to_str().unwrap())
         .collect::<Vec<_>>()
         .join(" ");
-    let output = std::process::Command::new(&bench_path)
+    let output = std::process::Command::new(bench_path.as_ref())
         .args(args)
         .output()
         .with_context(
             || format!("Error running {} {}: ", bench_path, args_string))?;
-    if !output.stderr.is_empty() {
-        bail!(String::from_utf8(output.stderr)?);
+    // resctl-bench logs warnings to stderr, only fail on the exit status.
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        bail!("{} {} failed ({}): {}", bench_path, args_string, output.status, stderr);
+    }
+    if !stderr.is_empty() {
+        eprint!("{}", stderr);
     }
     String::from_utf8(output.stdout).map_err(|e| anyhow!(e))
 }
@@ -368,16 +903,69 @@ pub fn database_directory(version: &str, model_name: &str) -> PathBuf {
 /// Returns a file path for a merged result file for a specific
 /// resctl-bench `version` and HD `model_name`, with an optional
 /// `detail` string.
-/// It ensures that a "merged-results" output directory exists, creating
-/// it if necessary.
+/// The file is only created when an external resctl-bench binary does
+/// the merge.
 pub fn merged_file(version: &str, model_name: &str, detail: Option<&str>)-> PathBuf {
-    fs::create_dir_all("merged-results").expect("Failed to create merged results dir");
-    let detail = match detail {
-        Some(d) => d,
-        None => "",
-    };
-    PathBuf::from("merged-results").join(&format!(
+    let detail = detail.unwrap_or_default();
+    PathBuf::from("merged-results").join(format!(
         "{}-{}-{}-merged-results.json.gz",
         version, model_name, detail
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn external(dir: &Path, jobs: JsonValue) -> ResultSet {
+        let path = dir.join("merged.json.gz");
+        let mut encoder = libflate::gzip::Encoder::new(fs::File::create(&path).unwrap()).unwrap();
+        encoder.write_all(jobs.dump().as_bytes()).unwrap();
+        encoder.finish().into_result().unwrap();
+        ResultSet::External {
+            version: "2.1".to_string(),
+            path,
+        }
+    }
+
+    #[test]
+    fn test_external_tune_result() {
+        let dir = tempfile::tempdir().unwrap();
+        let merged = external(
+            dir.path(),
+            json::array![
+                { spec: { kind: "storage" } },
+                {
+                    spec: { kind: "iocost-tune" },
+                    result: {
+                        data: { MOF: { data: [[1, 1], [2, 2]], outliers: [[3, 3]] } },
+                        confidence: { naive: { low: false }, isolation: { low: true } },
+                    },
+                },
+            ],
+        );
+        assert_eq!(merged.data_points().unwrap(), 3);
+        assert_eq!(merged.low_confidence().unwrap(), vec!["isolation"]);
+
+        let merged = external(dir.path(), json::array![{ spec: { kind: "storage" } }]);
+        assert!(merged.tune_result().is_err());
+        assert!(merged.data_points().is_err());
+        assert!(merged.low_confidence().is_err());
+    }
+
+    #[test]
+    fn test_model_vendor() {
+        for (model, vendor) in [
+            ("Samsung SSD 970 EVO Plus 1TB", "Samsung"),
+            ("MZUL2256HCHQ-00AFB", "Samsung"),
+            ("WDC WDS100T2B0C", "Western Digital"),
+            ("WD_BLACK SN850X 1000GB", "Western Digital"),
+            ("CT1000P3PSSD8", "Crucial"),
+            ("HFS256GD9TNG-62A0A", "SK hynix"),
+            ("Fanxiang S500Pro 1TB", "Fanxiang"),
+            ("Fanxiang_S500Pro_1TB", "Fanxiang"),
+        ] {
+            assert_eq!(model_vendor(model), vendor, "{}", model);
+        }
+    }
+}
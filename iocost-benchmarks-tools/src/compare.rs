@@ -0,0 +1,310 @@
+use anyhow::{Context, Result};
+use json::JsonValue;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use iocost_benchmarks::common::{version_key, BenchMerge};
+
+/// Relative changes larger than this are flagged by default
+pub const DFL_THRESHOLD: f64 = 0.25;
+
+/// A model or QoS parameter of a solution across versions
+#[derive(Debug, Serialize)]
+pub struct ParamComparison {
+    pub param: &'static str,
+    /// Value in each compared version, `None` if the version doesn't
+    /// have the solution
+    pub values: Vec<Option<f64>>,
+    /// Relative change from the previous version, `None` for the first
+    /// version, if either value is missing or if the previous one is 0
+    pub deltas: Vec<Option<f64>>,
+    /// A change is larger than the threshold or the solution appeared or
+    /// disappeared
+    pub flagged: bool,
+}
+
+/// A named solution, e.g. ISOLATED_BANDWIDTH, across versions
+#[derive(Debug, Serialize)]
+pub struct SolutionComparison {
+    pub solution: String,
+    pub params: Vec<ParamComparison>,
+}
+
+/// The solutions of a model across the versions it has merges for
+#[derive(Debug, Serialize)]
+pub struct ModelComparison {
+    pub model: String,
+    /// Compared resctl-bench versions (X.Y), oldest first
+    pub versions: Vec<String>,
+    pub solutions: Vec<SolutionComparison>,
+}
+
+impl ModelComparison {
+    pub fn nr_flagged(&self) -> usize {
+        self.solutions
+            .iter()
+            .flat_map(|s| s.params.iter())
+            .filter(|p| p.flagged)
+            .count()
+    }
+}
+
+/// Model and QoS parameters of a solution, as named in
+/// `IoCostModelParams` and `IoCostQoSParams`
+const PARAMS: [(&str, &str); 12] = [
+    ("model", "rbps"),
+    ("model", "rseqiops"),
+    ("model", "rrandiops"),
+    ("model", "wbps"),
+    ("model", "wseqiops"),
+    ("model", "wrandiops"),
+    ("qos", "rpct"),
+    ("qos", "rlat"),
+    ("qos", "wpct"),
+    ("qos", "wlat"),
+    ("qos", "min"),
+    ("qos", "max"),
+];
+
+/// Returns the model and QoS parameters of `solution`, an entry of the
+/// iocost-tune `solutions` result, in the order of `PARAMS`. `None` if
+/// the solution is missing.
+fn solution_params(solution: &JsonValue) -> Option<Vec<f64>> {
+    if solution.is_null() {
+        return None;
+    }
+    PARAMS
+        .iter()
+        .map(|(group, param)| solution[*group][*param].as_f64())
+        .collect()
+}
+
+/// Returns the relative change from `old` to `new`.
+fn relative_delta(old: f64, new: f64) -> Option<f64> {
+    if old == new {
+        Some(0.0)
+    } else if old == 0.0 {
+        None
+    } else {
+        Some((new - old) / old)
+    }
+}
+
+/// Compares the solutions of `merges`, the merges of `model` for
+/// different resctl-bench versions. Returns `None` if there are fewer
+/// than two versions.
+pub fn compare_model(
+    model: &str,
+    merges: &[BenchMerge],
+    threshold: f64,
+) -> Result<Option<ModelComparison>> {
+    if merges.len() < 2 {
+        return Ok(None);
+    }
+    let mut versions = vec![];
+    for merge in merges {
+        let result = merge
+            .merged
+            .tune_result()
+            .with_context(|| format!("{} {}", merge.version_str, model))?;
+        versions.push((merge.version_str.as_str(), result["solutions"].clone()));
+    }
+    Ok(compare_solutions(model, versions, threshold))
+}
+
+/// Compares the iocost-tune `solutions` result of each resctl-bench
+/// version in `versions`. Changes larger than `threshold` relative to
+/// the previous version are flagged. Returns `None` if there are fewer
+/// than two versions.
+fn compare_solutions(
+    model: &str,
+    mut versions: Vec<(&str, JsonValue)>,
+    threshold: f64,
+) -> Option<ModelComparison> {
+    if versions.len() < 2 {
+        return None;
+    }
+    versions.sort_by_key(|(version, _)| version_key(version));
+
+    let names: BTreeSet<&str> = versions
+        .iter()
+        .flat_map(|(_, solutions)| solutions.entries().map(|(name, _)| name))
+        .collect();
+    let mut solutions = vec![];
+    for name in names {
+        let per_version: Vec<Option<Vec<f64>>> = versions
+            .iter()
+            .map(|(_, solutions)| solution_params(&solutions[name]))
+            .collect();
+        let mut params = vec![];
+        for (idx, (_, param)) in PARAMS.iter().enumerate() {
+            let values: Vec<Option<f64>> = per_version
+                .iter()
+                .map(|params| params.as_ref().map(|p| p[idx]))
+                .collect();
+            let mut deltas = vec![None];
+            let mut flagged = false;
+            for pair in values.windows(2) {
+                let delta = match (pair[0], pair[1]) {
+                    (Some(old), Some(new)) => {
+                        let delta = relative_delta(old, new);
+                        flagged |= delta.is_none_or(|d| d.abs() > threshold);
+                        delta
+                    }
+                    // The solution appeared or disappeared.
+                    (Some(_), None) | (None, Some(_)) => {
+                        flagged = true;
+                        None
+                    }
+                    (None, None) => None,
+                };
+                deltas.push(delta);
+            }
+            params.push(ParamComparison {
+                param,
+                values,
+                deltas,
+                flagged,
+            });
+        }
+        solutions.push(SolutionComparison {
+            solution: name.to_string(),
+            params,
+        });
+    }
+
+    Some(ModelComparison {
+        model: model.to_string(),
+        versions: versions.iter().map(|(v, _)| v.to_string()).collect(),
+        solutions,
+    })
+}
+
+/// Formats `comparisons` as markdown, one table per solution with the
+/// flagged changes in bold.
+pub fn format_markdown(comparisons: &[ModelComparison], threshold: f64) -> Result<String> {
+    let mut out = String::new();
+    let nr_flagged: usize = comparisons.iter().map(|c| c.nr_flagged()).sum();
+    writeln!(out, "# Solution changes between resctl-bench versions\n")?;
+    writeln!(
+        out,
+        "{} models compared, {} parameters changed by more than {:.0}%.",
+        comparisons.len(),
+        nr_flagged,
+        threshold * 100.0
+    )?;
+
+    for cmp in comparisons {
+        writeln!(out, "\n## {} ({})", cmp.model, cmp.versions.join(", "))?;
+        for sol in &cmp.solutions {
+            writeln!(out, "\n### {}\n", sol.solution)?;
+            write!(out, "| Param | {} |", cmp.versions[0])?;
+            for version in &cmp.versions[1..] {
+                write!(out, " {} | Change |", version)?;
+            }
+            writeln!(out, "\n|{}", "---|".repeat(cmp.versions.len() * 2))?;
+            for param in &sol.params {
+                write!(out, "| {} |", param.param)?;
+                for (idx, value) in param.values.iter().enumerate() {
+                    match value {
+                        Some(value) => write!(out, " {} |", value)?,
+                        None => write!(out, " - |")?,
+                    }
+                    if idx == 0 {
+                        continue;
+                    }
+                    let delta = match (param.deltas[idx], param.values[idx - 1], value) {
+                        (Some(delta), _, _) if delta.abs() > threshold => {
+                            format!("**{:+.1}%**", delta * 100.0)
+                        }
+                        (Some(delta), _, _) => format!("{:+.1}%", delta * 100.0),
+                        (None, Some(_), Some(_)) => "**from 0**".into(),
+                        (None, Some(_), None) => "**removed**".into(),
+                        (None, None, Some(_)) => "**added**".into(),
+                        (None, None, None) => "-".into(),
+                    };
+                    write!(out, " {} |", delta)?;
+                }
+                writeln!(out)?;
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solution(rbps: u64, rlat: u64, max: f64) -> JsonValue {
+        json::object! {
+            model: {
+                rbps: rbps, rseqiops: 1000, rrandiops: 1000,
+                wbps: 1000, wseqiops: 1000, wrandiops: 1000,
+            },
+            qos: { rpct: 95.0, rlat: rlat, wpct: 95.0, wlat: 1000, min: 50.0, max: max },
+        }
+    }
+
+    fn param<'a>(cmp: &'a ModelComparison, sol: usize, name: &str) -> &'a ParamComparison {
+        cmp.solutions[sol]
+            .params
+            .iter()
+            .find(|p| p.param == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_compare_solutions() {
+        let versions = vec![
+            (
+                "2.2",
+                json::object! {
+                    naive: solution(1000, 1000, 100.0),
+                    isolation: solution(1000, 1200, 100.0),
+                    bandwidth: solution(1000, 1000, 100.0),
+                },
+            ),
+            (
+                "2.1",
+                json::object! {
+                    naive: solution(1000, 1000, 100.0),
+                    isolation: solution(1000, 1000, 80.0),
+                },
+            ),
+        ];
+        assert!(compare_solutions("model", versions[..1].to_vec(), 0.25).is_none());
+
+        let cmp = compare_solutions("model", versions, 0.25).unwrap();
+        assert_eq!(cmp.versions, vec!["2.1", "2.2"]);
+        let names: Vec<&str> = cmp.solutions.iter().map(|s| s.solution.as_str()).collect();
+        assert_eq!(names, vec!["bandwidth", "isolation", "naive"]);
+
+        // bandwidth is new in 2.2
+        assert_eq!(param(&cmp, 0, "rbps").values, vec![None, Some(1000.0)]);
+        assert!(param(&cmp, 0, "rbps").flagged);
+        // isolation rlat changed by 20%, max by 25%, both under the threshold
+        assert_eq!(param(&cmp, 1, "rlat").deltas, vec![None, Some(0.2)]);
+        assert!(!param(&cmp, 1, "rlat").flagged);
+        assert_eq!(param(&cmp, 1, "max").deltas, vec![None, Some(0.25)]);
+        assert!(!param(&cmp, 1, "max").flagged);
+        assert_eq!(param(&cmp, 2, "wlat").deltas, vec![None, Some(0.0)]);
+        assert_eq!(cmp.nr_flagged(), PARAMS.len());
+
+        let cmp = compare_solutions(
+            "model",
+            vec![
+                ("2.1", json::object! { naive: solution(1000, 1000, 100.0) }),
+                ("2.2", json::object! { naive: solution(500, 1000, 100.0) }),
+            ],
+            0.25,
+        )
+        .unwrap();
+        assert_eq!(param(&cmp, 0, "rbps").deltas, vec![None, Some(-0.5)]);
+        assert_eq!(cmp.nr_flagged(), 1);
+        assert!(format_markdown(&[cmp], 0.25)
+            .unwrap()
+            .contains("| rbps | 1000 | 500 | **-50.0%** |"));
+    }
+}
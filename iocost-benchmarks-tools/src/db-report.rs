@@ -0,0 +1,356 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use glob::glob;
+use resctl_bench::hwdb::Hwdb;
+use resctl_bench::model_name;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use iocost_benchmarks::common::{
+    load_json, model_vendor, source_date, version_and_model, version_key, ResultInfo,
+};
+
+/// Returns the size in `bytes` in decimal GB, the way drives are sold.
+fn size_gb(bytes: u64) -> u64 {
+    (bytes as f64 / 1e9).round() as u64
+}
+
+/// Statistics of the results of a model in the database
+#[derive(Debug, Default, Serialize)]
+struct ModelReport {
+    vendor: String,
+    /// Device sizes, see `size_gb()`
+    sizes: BTreeSet<u64>,
+    /// Number of results per resctl-bench version (X.Y)
+    submissions: BTreeMap<String, usize>,
+    fwrevs: BTreeSet<String>,
+    kernel_versions: BTreeSet<String>,
+    /// Dates of the oldest and newest results, in RFC 3339 format
+    first_date: String,
+    last_date: String,
+    /// Whether the hwdb file has fwrev-specific stanzas for the model,
+    /// unknown without a hwdb file
+    fwrev_stanza: Option<bool>,
+    /// Model strings reported by the devices
+    #[serde(skip)]
+    model_strings: BTreeSet<String>,
+}
+
+/// Number of models and results of a vendor or a size
+#[derive(Debug, Default, Serialize)]
+struct Breakdown {
+    models: usize,
+    results: usize,
+}
+
+/// The whole report, written as JSON and formatted as markdown
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    /// When the report was generated, see `source_date()`
+    date: String,
+    /// resctl-bench versions (X.Y) found in the database, oldest first
+    versions: Vec<String>,
+    results: usize,
+    models: BTreeMap<String, ModelReport>,
+    vendors: BTreeMap<String, Breakdown>,
+    /// Breakdown by device size, see `size_gb()`
+    sizes: BTreeMap<u64, Breakdown>,
+    /// Models without any result of the latest version, which need to be
+    /// benchmarked again
+    outdated: Vec<String>,
+}
+
+impl Report {
+    /// Adds the result file in `path` to the report.
+    fn add_result(&mut self, path: &Path) -> Result<()> {
+        let (version, model) = version_and_model(path);
+        let result = load_json(&path.to_string_lossy())
+            .with_context(|| format!("Failed to load {:?}", path))?;
+        let info = ResultInfo::from_result(path, &result);
+        let report = &result[0]["sysinfo"]["sysreqs_report"];
+        let model_string = report["scr_dev_model"].to_string();
+
+        let entry = self.models.entry(model).or_default();
+        if entry.vendor.is_empty() {
+            entry.vendor = model_vendor(&model_string);
+        }
+        if let Some(size) = report["scr_dev_size"].as_u64() {
+            entry.sizes.insert(size_gb(size));
+        }
+        *entry.submissions.entry(version).or_default() += 1;
+        entry.fwrevs.insert(info.fwrev);
+        entry.kernel_versions.insert(info.kernel_version);
+        if entry.first_date.is_empty() || info.date < entry.first_date {
+            entry.first_date = info.date.clone();
+        }
+        if info.date > entry.last_date {
+            entry.last_date = info.date;
+        }
+        entry.model_strings.insert(model_string);
+        self.results += 1;
+        Ok(())
+    }
+
+    /// Fills in the fields derived from the per-model statistics and,
+    /// if available, from `hwdb`.
+    fn finish(&mut self, hwdb: Option<&Hwdb>) {
+        let mut versions = BTreeSet::new();
+        for model in self.models.values_mut() {
+            versions.extend(model.submissions.keys().cloned());
+            let results: usize = model.submissions.values().sum();
+            let add = |breakdown: &mut Breakdown| {
+                breakdown.models += 1;
+                breakdown.results += results;
+            };
+            add(self.vendors.entry(model.vendor.clone()).or_default());
+            for size in &model.sizes {
+                add(self.sizes.entry(*size).or_default());
+            }
+            model.fwrev_stanza = hwdb.map(|hwdb| {
+                let keys: Vec<String> =
+                    model.model_strings.iter().map(|m| model_name::hwdb_key(m)).collect();
+                hwdb.stanzas
+                    .iter()
+                    .flat_map(|s| s.matches.iter())
+                    .any(|m| keys.contains(&m.model) && m.fwrev != "*")
+            });
+        }
+        self.versions = versions.into_iter().collect();
+        self.versions.sort_by_key(|v| version_key(v));
+        if let Some(latest) = self.versions.last() {
+            self.outdated = self
+                .models
+                .iter()
+                .filter(|(_, model)| !model.submissions.contains_key(latest))
+                .map(|(name, _)| name.clone())
+                .collect();
+        }
+    }
+
+    /// Formats the report as markdown.
+    fn format_markdown(&self) -> Result<String> {
+        let mut out = String::new();
+        let date = |date: &str| date.get(..10).unwrap_or_default().to_string();
+        let join = |set: &BTreeSet<String>| set.iter().cloned().collect::<Vec<_>>().join(", ");
+
+        writeln!(out, "# iocost-benchmarks database report\n")?;
+        writeln!(
+            out,
+            "{} results of {} models, generated on {}.\n",
+            self.results,
+            self.models.len(),
+            date(&self.date)
+        )?;
+
+        writeln!(out, "## Models\n")?;
+        write!(out, "| Model | Vendor | Size |")?;
+        for version in &self.versions {
+            write!(out, " {} |", version)?;
+        }
+        writeln!(out, " Firmware revisions | Kernel versions | Dates | fwrev stanza |")?;
+        writeln!(out, "|{}", "---|".repeat(self.versions.len() + 7))?;
+        for (name, model) in &self.models {
+            let sizes: Vec<String> = model.sizes.iter().map(|gb| format!("{} GB", gb)).collect();
+            write!(out, "| {} | {} | {} |", name, model.vendor, sizes.join(", "))?;
+            for version in &self.versions {
+                write!(out, " {} |", model.submissions.get(version).unwrap_or(&0))?;
+            }
+            let fwrev_stanza = match model.fwrev_stanza {
+                Some(true) => "yes",
+                Some(false) => "no",
+                None => "?",
+            };
+            writeln!(
+                out,
+                " {} | {} | {} - {} | {} |",
+                join(&model.fwrevs),
+                join(&model.kernel_versions),
+                date(&model.first_date),
+                date(&model.last_date),
+                fwrev_stanza
+            )?;
+        }
+
+        let vendors = self.vendors.iter().map(|(vendor, b)| (vendor.clone(), b));
+        let sizes = self.sizes.iter().map(|(gb, b)| (format!("{} GB", gb), b));
+        for (title, breakdown) in [("Vendor", vendors.collect::<Vec<_>>()), ("Size", sizes.collect())] {
+            writeln!(out, "\n## {}s\n", title)?;
+            writeln!(out, "| {} | Models | Results |\n|---|---|---|", title)?;
+            for (key, b) in breakdown {
+                writeln!(out, "| {} | {} | {} |", key, b.models, b.results)?;
+            }
+        }
+
+        if let Some(latest) = self.versions.last() {
+            writeln!(out, "\n## Models without {} results\n", latest)?;
+            if self.outdated.is_empty() {
+                writeln!(out, "None.")?;
+            }
+            for name in &self.outdated {
+                let submissions: Vec<String> = self.models[name]
+                    .submissions
+                    .iter()
+                    .map(|(version, nr)| format!("{}: {}", version, nr))
+                    .collect();
+                writeln!(out, "- {} ({})", name, submissions.join(", "))?;
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+/// Reports statistics and coverage of the resctl-bench results database
+///
+/// Writes a markdown and a JSON report with the number of results per
+/// model and version, the firmware and kernel versions and dates they
+/// span, and the vendors and sizes of the models.
+struct Cli {
+    /// Database dir to report on
+    #[arg(short, long, value_name = "DIR", default_value = "database")]
+    database_dir: String,
+
+    /// hwdb file generated by merge-results, used to tell which models
+    /// have fwrev-specific solutions. Skipped if it doesn't exist.
+    #[arg(long, value_name = "FILE", default_value = "90-iocost-tune.hwdb")]
+    hwdb: String,
+
+    /// File to write the markdown report to
+    #[arg(long, value_name = "FILE", default_value = "db-report.md")]
+    markdown: String,
+
+    /// File to write the JSON report to
+    #[arg(long, value_name = "FILE", default_value = "db-report.json")]
+    json: String,
+}
+
+fn main() -> Result<()> {
+    let args = Cli::parse();
+    let mut report = Report {
+        date: source_date().to_rfc3339(),
+        ..Default::default()
+    };
+
+    // See merge-results for the layout of the database.
+    for path in glob(&format!("{}/*/*/result-*.json.gz", args.database_dir))?.flatten() {
+        report.add_result(&path)?;
+    }
+
+    let hwdb = match fs::read_to_string(&args.hwdb) {
+        Ok(text) => Some(Hwdb::parse(&text)),
+        Err(_) => {
+            println!("{} not found, fwrev stanzas won't be reported", &args.hwdb);
+            None
+        }
+    };
+    report.finish(hwdb.as_ref());
+
+    fs::write(&args.markdown, report.format_markdown()?)?;
+    fs::write(&args.json, serde_json::to_string_pretty(&report)?)?;
+    println!(
+        "{} results of {} models, see {} and {}",
+        report.results,
+        report.models.len(),
+        &args.markdown,
+        &args.json
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write as _;
+    use std::path::PathBuf;
+
+    /// Writes a result file of `model` for `version` in the database in
+    /// `dir`, finished at `end`.
+    fn write_result(
+        dir: &Path,
+        version: &str,
+        model: &str,
+        fwrev: &str,
+        size: u64,
+        end: i64,
+    ) -> PathBuf {
+        let model_dir = dir.join(version).join(model_name::dir_name(model));
+        fs::create_dir_all(&model_dir).unwrap();
+        let path = model_dir.join(format!("result-{}.json.gz", end));
+        let result = json::array![{
+            period: [end - 3600, end],
+            sysinfo: {
+                sysreqs_report: {
+                    scr_dev_model: model,
+                    scr_dev_fwrev: fwrev,
+                    scr_dev_size: size,
+                    kernel_version: "6.1.0",
+                },
+            },
+        }];
+        let mut encoder = libflate::gzip::Encoder::new(fs::File::create(&path).unwrap()).unwrap();
+        encoder.write_all(result.dump().as_bytes()).unwrap();
+        encoder.finish().into_result().unwrap();
+        path
+    }
+
+    #[test]
+    fn test_size_gb() {
+        assert_eq!(size_gb(0), 0);
+        assert_eq!(size_gb(1_000_204_886_016), 1000);
+        assert_eq!(size_gb(256_060_514_304), 256);
+        assert_eq!(size_gb(499_999_999), 0);
+        assert_eq!(size_gb(500_000_000), 1);
+    }
+
+    #[test]
+    fn test_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let samsung = "Samsung SSD 970 EVO Plus 1TB";
+        let oem = "MZUL2256HCHQ-00AFB";
+        let paths = [
+            write_result(dir.path(), "2.1", samsung, "1B2QEXM7", 1_000_204_886_016, 1_600_000_000),
+            write_result(dir.path(), "2.2", samsung, "2B2QEXM7", 1_000_204_886_016, 1_700_000_000),
+            write_result(dir.path(), "2.1", oem, "3L1QEXH7", 256_060_514_304, 1_650_000_000),
+        ];
+
+        let mut report = Report::default();
+        for path in &paths {
+            report.add_result(path).unwrap();
+        }
+        let hwdb = Hwdb::parse(&format!(
+            "block:*:name:{}:fwrev:2B2QEXM7:\n  IOCOST_SOLUTIONS=naive\n",
+            model_name::hwdb_key(samsung)
+        ));
+        report.finish(Some(&hwdb));
+
+        assert_eq!(report.results, 3);
+        assert_eq!(report.versions, vec!["2.1", "2.2"]);
+
+        let model = &report.models[&model_name::dir_name(samsung)];
+        assert_eq!(model.vendor, "Samsung");
+        assert_eq!(model.sizes, BTreeSet::from([1000]));
+        assert_eq!(model.submissions, BTreeMap::from([("2.1".into(), 1), ("2.2".into(), 1)]));
+        assert_eq!(model.fwrevs, BTreeSet::from(["1B2QEXM7".into(), "2B2QEXM7".into()]));
+        assert!(model.first_date.starts_with("2020-09-13"));
+        assert!(model.last_date.starts_with("2023-11-14"));
+        assert_eq!(model.fwrev_stanza, Some(true));
+
+        let model = &report.models[&model_name::dir_name(oem)];
+        assert_eq!(model.vendor, "Samsung");
+        assert_eq!(model.fwrev_stanza, Some(false));
+
+        assert_eq!(report.vendors["Samsung"].models, 2);
+        assert_eq!(report.vendors["Samsung"].results, 3);
+        assert_eq!(report.sizes[&1000].results, 2);
+        assert_eq!(report.sizes[&256].results, 1);
+        assert_eq!(report.outdated, vec![model_name::dir_name(oem)]);
+
+        let markdown = report.format_markdown().unwrap();
+        assert!(markdown.contains("3 results of 2 models"));
+        assert!(markdown.contains("## Models without 2.2 results"));
+    }
+}
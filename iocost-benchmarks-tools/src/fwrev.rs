@@ -0,0 +1,282 @@
+use serde::Deserialize;
+use std::cmp::Ordering;
+
+use crate::common::model_vendor;
+
+/// How the firmware revisions of a model are ordered to find the latest
+/// one, set with `fwrev_order` in the [policy] section of the config.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FwrevOrder {
+    /// Use the first vendor-specific scheme which understands all the
+    /// firmware revisions of the model, falling back to natural
+    #[default]
+    Auto,
+    /// Plain string comparison, the way fwupd compares NVMe firmware
+    /// revisions with its PLAIN format
+    Plain,
+    /// Like plain, but runs of digits are compared as numbers
+    Natural,
+}
+
+/// A way of ordering the firmware revisions of a model
+trait FwrevScheme: Sync {
+    fn name(&self) -> &'static str;
+    /// Whether the scheme understands `fwrev` of `model`
+    fn accepts(&self, model: &str, fwrev: &str) -> bool;
+    /// Compares two firmware revisions accepted by the scheme
+    fn cmp(&self, a: &str, b: &str) -> Ordering;
+}
+
+struct Plain;
+
+impl FwrevScheme for Plain {
+    fn name(&self) -> &'static str {
+        "plain"
+    }
+
+    fn accepts(&self, _model: &str, _fwrev: &str) -> bool {
+        true
+    }
+
+    fn cmp(&self, a: &str, b: &str) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+struct Natural;
+
+impl Natural {
+    /// Splits `fwrev` in runs of digits and of other characters.
+    fn chunks(fwrev: &str) -> Vec<&str> {
+        let mut chunks = vec![];
+        let mut start = 0;
+        let mut prev_digit = None;
+        for (idx, c) in fwrev.char_indices() {
+            let digit = c.is_ascii_digit();
+            if prev_digit.is_some_and(|prev| prev != digit) {
+                chunks.push(&fwrev[start..idx]);
+                start = idx;
+            }
+            prev_digit = Some(digit);
+        }
+        if start < fwrev.len() {
+            chunks.push(&fwrev[start..]);
+        }
+        chunks
+    }
+}
+
+impl FwrevScheme for Natural {
+    fn name(&self) -> &'static str {
+        "natural"
+    }
+
+    fn accepts(&self, _model: &str, _fwrev: &str) -> bool {
+        true
+    }
+
+    fn cmp(&self, a: &str, b: &str) -> Ordering {
+        for (ca, cb) in Self::chunks(a).iter().zip(Self::chunks(b).iter()) {
+            let ord = match (ca.parse::<u64>(), cb.parse::<u64>()) {
+                (Ok(na), Ok(nb)) => na.cmp(&nb),
+                _ => ca.cmp(cb),
+            };
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        }
+        Self::chunks(a).len().cmp(&Self::chunks(b).len()).then(a.cmp(b))
+    }
+}
+
+fn is_samsung(model: &str) -> bool {
+    model_vendor(model) == "Samsung"
+}
+
+/// Samsung NVMe firmware revisions look like 2B2QEXM7: the revision
+/// digit, a letter and a digit identifying the firmware branch, and the
+/// product code, QEXM7 for the 970 EVO Plus here.
+struct SamsungNvme;
+
+impl SamsungNvme {
+    /// Returns the revision, the branch letter and digit, and the product
+    /// code of `fwrev`.
+    fn fields(fwrev: &str) -> Option<(u32, char, u32, &str)> {
+        let b = fwrev.as_bytes();
+        if b.len() != 8
+            || !b[1].is_ascii_uppercase()
+            || !b[3..].iter().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+        {
+            return None;
+        }
+        let digit = |c: u8| (c as char).to_digit(10);
+        Some((digit(b[0])?, b[1] as char, digit(b[2])?, &fwrev[3..]))
+    }
+}
+
+impl FwrevScheme for SamsungNvme {
+    fn name(&self) -> &'static str {
+        "samsung-nvme"
+    }
+
+    fn accepts(&self, model: &str, fwrev: &str) -> bool {
+        is_samsung(model) && Self::fields(fwrev).is_some()
+    }
+
+    fn cmp(&self, a: &str, b: &str) -> Ordering {
+        Self::fields(a).cmp(&Self::fields(b))
+    }
+}
+
+/// Samsung SATA firmware revisions look like RVT04B6Q: the product code,
+/// RVT for the 860 EVO here, the two digit revision and a suffix.
+struct SamsungSata;
+
+impl SamsungSata {
+    /// Returns the revision, the product code and the suffix of `fwrev`.
+    fn fields(fwrev: &str) -> Option<(u32, &str, &str)> {
+        let b = fwrev.as_bytes();
+        if b.len() != 8
+            || !b[..3].iter().all(|c| c.is_ascii_uppercase())
+            || !b[3..5].iter().all(|c| c.is_ascii_digit())
+            || !b[5..].iter().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+        {
+            return None;
+        }
+        Some((fwrev[3..5].parse().ok()?, &fwrev[..3], &fwrev[5..]))
+    }
+}
+
+impl FwrevScheme for SamsungSata {
+    fn name(&self) -> &'static str {
+        "samsung-sata"
+    }
+
+    fn accepts(&self, model: &str, fwrev: &str) -> bool {
+        is_samsung(model) && Self::fields(fwrev).is_some()
+    }
+
+    fn cmp(&self, a: &str, b: &str) -> Ordering {
+        Self::fields(a).cmp(&Self::fields(b))
+    }
+}
+
+/// Vendor-specific schemes tried in order by FwrevOrder::Auto
+const VENDOR_SCHEMES: &[&dyn FwrevScheme] = &[&SamsungNvme, &SamsungSata];
+
+/// Sorts `fwrevs` of `model` from the oldest to the latest according to
+/// `order` and returns the name of the scheme used.
+pub fn sort_fwrevs(order: FwrevOrder, model: &str, fwrevs: &mut [String]) -> &'static str {
+    let scheme: &dyn FwrevScheme = match order {
+        FwrevOrder::Plain => &Plain,
+        FwrevOrder::Natural => &Natural,
+        FwrevOrder::Auto => VENDOR_SCHEMES
+            .iter()
+            .copied()
+            .find(|s| fwrevs.iter().all(|f| s.accepts(model, f)))
+            .unwrap_or(&Natural),
+    };
+    fwrevs.sort_by(|a, b| scheme.cmp(a, b));
+    scheme.name()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sort_fwrevs, FwrevOrder};
+
+    /// (order, model, fwrevs, expected scheme, expected order)
+    type Case<'a> = (FwrevOrder, &'a str, &'a [&'a str], &'a str, &'a [&'a str]);
+
+    #[test]
+    fn test_sort_fwrevs() {
+        let cases: &[Case] = &[
+            (
+                FwrevOrder::Plain,
+                "WDC WDS100T2B0C",
+                &["211070WD", "21107WD", "111110WD"],
+                "plain",
+                &["111110WD", "211070WD", "21107WD"],
+            ),
+            (
+                FwrevOrder::Natural,
+                "WDC WDS100T2B0C",
+                &["211070WD", "21107WD", "111110WD"],
+                "natural",
+                &["21107WD", "111110WD", "211070WD"],
+            ),
+            (
+                FwrevOrder::Natural,
+                "CT1000P3PSSD8",
+                &["P9CR40A", "P9CR310", "P9CR30A", "P9CR3"],
+                "natural",
+                &["P9CR3", "P9CR30A", "P9CR40A", "P9CR310"],
+            ),
+            (
+                FwrevOrder::Auto,
+                "Samsung SSD 970 EVO Plus 1TB",
+                &["4B2QEXM7", "1B2QEXM7", "2B2QEXM7", "3B2QEXM7"],
+                "samsung-nvme",
+                &["1B2QEXM7", "2B2QEXM7", "3B2QEXM7", "4B2QEXM7"],
+            ),
+            (
+                // Samsung OEM models don't start with the vendor name.
+                FwrevOrder::Auto,
+                "MZUL2256HCHQ-00AFB",
+                &["3L1QEXH7", "2L2QEXH7", "1L0QEXH7"],
+                "samsung-nvme",
+                &["1L0QEXH7", "2L2QEXH7", "3L1QEXH7"],
+            ),
+            (
+                // The revision digit comes before the branch.
+                FwrevOrder::Auto,
+                "Samsung SSD 970 EVO 1TB",
+                &["2B2QEXE7", "1B1QCXE7", "1C1QCXE7"],
+                "samsung-nvme",
+                &["1B1QCXE7", "1C1QCXE7", "2B2QEXE7"],
+            ),
+            (
+                FwrevOrder::Auto,
+                "Samsung SSD 860 EVO 1TB",
+                &["RVT04B6Q", "RVT01B6Q", "RVT03B6Q", "RVT02B6Q"],
+                "samsung-sata",
+                &["RVT01B6Q", "RVT02B6Q", "RVT03B6Q", "RVT04B6Q"],
+            ),
+            (
+                // Not a Samsung
+                FwrevOrder::Auto,
+                "CT1000P3PSSD8",
+                &["2B2QEXM7", "1B2QEXM7"],
+                "natural",
+                &["1B2QEXM7", "2B2QEXM7"],
+            ),
+            (
+                // Mixed schemes fall back to natural.
+                FwrevOrder::Auto,
+                "Samsung SSD 970 EVO Plus 1TB",
+                &["2B2QEXM7", "RVT04B6Q", "1B2QEXM7", "10B2QEXM"],
+                "natural",
+                &["1B2QEXM7", "2B2QEXM7", "10B2QEXM", "RVT04B6Q"],
+            ),
+            (
+                FwrevOrder::Auto,
+                "Samsung SSD 860 EVO 1TB",
+                &["RVT04B6Q", "RVT3B6Q"],
+                "natural",
+                &["RVT3B6Q", "RVT04B6Q"],
+            ),
+        ];
+
+        for (order, model, input, scheme, expected) in cases.iter() {
+            let mut fwrevs: Vec<String> = input.iter().map(|f| f.to_string()).collect();
+            assert_eq!(
+                sort_fwrevs(*order, model, &mut fwrevs),
+                *scheme,
+                "{:?} {:?}",
+                order,
+                input
+            );
+            assert_eq!(&fwrevs, expected, "{:?} {:?}", order, input);
+        }
+    }
+}
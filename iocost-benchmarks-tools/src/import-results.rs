@@ -1,18 +1,21 @@
-use anyhow::{bail, Result, Context};
-use common::{load_json, merged_file, save_pdf_to, BenchMerge};
-use serde::{Serialize, Deserialize};
+use anyhow::{anyhow, bail, Result, Context};
+use iocost_benchmarks::common::{
+    load_json, merged_file, result_fingerprint, result_model_name, result_version, save_pdf_to,
+    BenchMerge, ResultSet,
+};
+use glob::glob;
+use serde::{Deserialize, Serialize};
 use serde_with::skip_serializing_none;
 use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::exit;
-use semver::VersionReq;
 use clap::Parser;
 
-use crate::common::{database_directory, run_resctl, BenchVersion};
+use iocost_benchmarks::common::{database_directory, load_config, set_version_registry, BenchVersion, TomlData};
+use iocost_benchmarks::quality::{Finding, QualityPolicy, Severity};
 
-mod common;
 
 static ALLOWED_PREFIXES: &[&str] = &[
     "https://github.com/",
@@ -38,12 +41,12 @@ fn get_urls(context: &json::JsonValue) -> Result<Vec<String>> {
     let issue = &context["event"]["issue"];
 
     // The workflow should already filter this out, but double-check.
-    if issue["locked"].as_bool().unwrap() || issue["state"] != "open" {
-        panic!("Issue is either locked or not in the open state, workflow should filter this...");
+    if issue["locked"].as_bool() != Some(false) || issue["state"] != "open" {
+        bail!("Issue is either locked or not in the open state, workflow should filter this...");
     }
 
     // created is always for comments, opened is always for issues.
-    let body = match context["event"]["action"].as_str().unwrap() {
+    let body = match context["event"]["action"].as_str().unwrap_or_default() {
         "created" => context["event"]["comment"]["body"].as_str(),
         "opened" => issue["body"].as_str(),
         "edited" => {
@@ -59,8 +62,13 @@ fn get_urls(context: &json::JsonValue) -> Result<Vec<String>> {
             context["event"]["action"]
         ),
     }
-    .expect("Could not obtain the contents of the issue or comment");
+    .ok_or_else(|| anyhow!("Could not obtain the contents of the issue or comment"))?;
 
+    Ok(find_urls(body))
+}
+
+/// Extracts the allowlisted result file URLs found in `body`.
+fn find_urls(body: &str) -> Vec<String> {
     let mut urls = vec![];
     for link in linkify::LinkFinder::new().links(body) {
         let link = link.as_str();
@@ -74,30 +82,112 @@ fn get_urls(context: &json::JsonValue) -> Result<Vec<String>> {
             );
         }
     }
-    Ok(urls)
+    urls
 }
 
-async fn download_url(url: &str) -> Result<String> {
-    let response = reqwest::get(url).await?;
-    let contents = response.bytes().await?;
+/// Extracts the issue number, if available, and the URLs found in a
+/// saved issue file. The file can contain either a Github workflow
+/// context (the contents of GITHUB_CONTEXT) or an issue or comment
+/// object as returned by the Github API.
+fn get_issue_file_urls(path: &str) -> Result<(Option<u64>, Vec<String>)> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Can't read issue file {}", path))?;
+    let issue = json::parse(&contents)
+        .with_context(|| format!("Error parsing issue file {}", path))?;
+
+    if issue.has_key("event") {
+        return Ok((issue["event"]["issue"]["number"].as_u64(), get_urls(&issue)?));
+    }
+
+    let body = match issue["body"].as_str() {
+        Some(body) => body,
+        None => bail!("No issue or comment body found in {}", path),
+    };
+    // Comments don't have a number, but link to their issue.
+    let number = issue["number"].as_u64().or_else(|| {
+        issue["issue_url"]
+            .as_str()
+            .and_then(|url| url.rsplit('/').next())
+            .and_then(|n| n.parse().ok())
+    });
+    Ok((number, find_urls(body)))
+}
+
+/// Reads a list of result file URLs or local paths, one per line.
+/// Empty lines and lines starting with '#' are ignored.
+fn get_list_file_links(path: &str) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Can't read list file {}", path))?;
+    let mut links = vec![];
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if Path::new(line).exists() || (is_url_allowlisted(line) && line.ends_with(".json.gz")) {
+            links.push(line.to_string());
+        } else {
+            println!("Link ignored, not an existing file nor an allowlisted URL: {}", line);
+        }
+    }
+    Ok(links)
+}
+
+/// Saves the result file `contents` in the `dir` directory and
+/// returns its path.
+fn save_result(dir: &Path, contents: &[u8]) -> Result<String> {
     // Use md5sum of the data as filename, we only care about exact duplicates.
-    let path = format!("result-{:x}.json.gz", md5::compute(&contents));
+    let path = dir
+        .join(format!("result-{:x}.json.gz", md5::compute(contents)))
+        .to_string_lossy()
+        .to_string();
     let mut file = fs::File::create(&path)?;
-    file.write_all(&contents)?;
+    file.write_all(contents)?;
     Ok(path)
 }
 
+async fn download_url(url: &str, dir: &Path) -> Result<String> {
+    let response = reqwest::get(url).await?.error_for_status()?;
+    let contents = response.bytes().await?;
+    save_result(dir, &contents)
+}
+
+/// Returns the path of the copy of the file at `url` in the `mirror`
+/// directory, laid out as <mirror>/<host>/<path> like `wget -x` does.
+fn mirror_path(mirror: &Path, url: &str) -> Result<PathBuf> {
+    let url = reqwest::Url::parse(url)?;
+    let host = match url.host_str() {
+        Some(host) => host,
+        None => bail!("No host in {}", url),
+    };
+    Ok(mirror.join(host).join(url.path().trim_start_matches('/')))
+}
+
+/// Fetches the result file in `link`, which can be a local path or a
+/// URL, into the `dir` directory and returns the path of the copy. If
+/// a `mirror` directory is specified, URLs are resolved against it
+/// instead of being downloaded, see `mirror_path()`.
+async fn fetch_result(link: &str, mirror: Option<&Path>, dir: &Path) -> Result<String> {
+    let local_path = match mirror {
+        _ if Path::new(link).exists() => PathBuf::from(link),
+        Some(mirror) => mirror_path(mirror, link)?,
+        None => return download_url(link, dir).await,
+    };
+    let contents = fs::read(&local_path)
+        .with_context(|| format!("Can't read result file {:?}", local_path))?;
+    save_result(dir, &contents)
+}
 
+/// Models a result file and its metadata file in the database
 #[skip_serializing_none]
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 struct BenchResult {
     /// Drive model name
     model_name: String,
     /// Path to the result directory in the database
-    #[serde(skip_serializing)]
+    #[serde(skip)]
     dir: String,
     /// Path to the source result file
-    #[serde(skip_serializing)]
+    #[serde(skip)]
     result_file: String,
     /// resctl-bench version used to generate the result (major.minor)
     version: String,
@@ -105,6 +195,11 @@ struct BenchResult {
     issue: Option<u64>,
     /// Result file url, if provided through a Github issue
     url: Option<String>,
+    /// Fingerprint of the result contents, see `result_fingerprint()`
+    fingerprint: Option<String>,
+    /// Github issues which submitted the same result again
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    duplicate_issues: Vec<u64>,
 }
 
 impl BenchResult {
@@ -114,21 +209,11 @@ impl BenchResult {
     async fn new(json_result_file: &str, database_path: &str)
     -> Result<Self>
     {
-        let result = load_json(&json_result_file)
-            .expect(&format!("Error parsing json file {}", &json_result_file));
-        let full_version = result[0]["sysinfo"]["bench_version"]
-            .to_string()
-            .split_whitespace()
-            .collect::<Vec<_>>()[0]
-            .to_string();
-        let version = {
-            let v = semver::Version::parse(&full_version)?;
-            format!("{}.{}", v.major, v.minor)
-        };
-        semver::Version::parse(&full_version)?;
-        let model_name = result[0]["sysinfo"]["sysreqs_report"]["scr_dev_model"]
-            .to_string()
-            .replace(" ", "_");
+        let result = load_json(json_result_file)
+            .with_context(|| format!("Error parsing json file {}", &json_result_file))?;
+        let version = result_version(&result)?;
+        let model_name = result_model_name(&result);
+        let fingerprint = result_fingerprint(&result);
         let dir = PathBuf::from(database_path)
             .join(&version)
             .join(&model_name)
@@ -140,27 +225,69 @@ impl BenchResult {
             result_file: json_result_file.to_string(),
             version,
             issue: None,
-            url: None
+            url: None,
+            fingerprint: Some(fingerprint),
+            duplicate_issues: vec![],
         })
     }
 
-    /// Runs resctl-demo to validate the file in self.path.
+    /// Loads the metadata of the result file `result_file` in the
+    /// database. Results imported before metadata files were
+    /// introduced get the metadata they would have been imported with.
+    async fn load(result_file: &Path, database_path: &str) -> Result<Self> {
+        let mut result = Self::new(&result_file.to_string_lossy(), database_path).await?;
+        // The model name in the result may not match the directory.
+        if let Some(dir) = result_file.parent() {
+            result.dir = dir.to_string_lossy().to_string();
+        }
+        let metadata_path = result.metadata_file_path();
+        if metadata_path.exists() {
+            let mut metadata: BenchResult = serde_json::from_str(&fs::read_to_string(&metadata_path)?)
+                .with_context(|| format!("Error parsing metadata file {:?}", metadata_path))?;
+            metadata.dir = result.dir;
+            metadata.result_file = result.result_file;
+            metadata.fingerprint = result.fingerprint;
+            result = metadata;
+        }
+        Ok(result)
+    }
+
+    /// Merges the file in self.result_file with resctl-bench to validate
+    /// it.
     fn validate(&self) -> Result<()> {
-        run_resctl(
+        ResultSet::merge(
             &self.version,
-            &["--result", "/tmp/result.json", "merge", &self.result_file],
+            &[PathBuf::from(&self.result_file)],
+            Path::new("/tmp/result.json"),
+            0,
         )?;
         Ok(())
     }
 
+    /// Checks the file in self.result_file against `policy` and returns
+    /// the rules it failed.
+    fn check_quality(&self, policy: &QualityPolicy) -> Result<Vec<Finding>> {
+        let result = load_json(&self.result_file)
+            .with_context(|| format!("Error parsing json file {}", &self.result_file))?;
+        Ok(policy.check(&result))
+    }
+
+    /// Returns the name of the result file
+    fn result_file_name(&self) -> &str {
+        Path::new(&self.result_file)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .expect("Malformed result path")
+    }
+
     /// Returns a path for the result file in the DB
     fn db_file(&self) -> PathBuf {
-        PathBuf::from(&self.dir).join(&self.result_file)
+        PathBuf::from(&self.dir).join(self.result_file_name())
     }
 
     /// Returns a path for the metadata file in the DB
     fn metadata_file_path(&self) -> PathBuf {
-        let basename = Path::new(&self.result_file)
+        let basename = Path::new(self.result_file_name())
             .with_extension("")
             .with_extension("")
             .to_str()
@@ -177,18 +304,18 @@ impl BenchResult {
     fn add_to_database(&self, id: Option<&str>) -> Result<()> {
         let pdfs_dir = match id {
             Some(id) => PathBuf::from(".")
-                .join(&format!("pdfs-for-{}", id)),
+                .join(format!("pdfs-for-{}", id)),
             None => {
                 PathBuf::from(".")
-                    .join(&format!("pdfs-for-{}-{}", &self.model_name, &self.version))
+                    .join(format!("pdfs-for-{}-{}", &self.model_name, &self.version))
             }
         };
         save_pdf_to(&self.version, &PathBuf::from(&self.result_file), &pdfs_dir, None)?;
         // Generate DB directory and place the result file there
         fs::create_dir_all(&self.dir).ok();
-        fs::rename(&self.result_file, &self.db_file())?;
+        fs::rename(&self.result_file, self.db_file())?;
         // Create metadata file and save it in the DB dir
-        let mut metadata_file = fs::File::create(&self.metadata_file_path())?;
+        let mut metadata_file = fs::File::create(self.metadata_file_path())?;
         write!(metadata_file, "{}", serde_json::to_string(self)?)?;
         Ok(())
     }
@@ -216,87 +343,565 @@ impl HighLevel {
 
     /// Runs resctl-bench to generate a high-level summary, if
     /// available, and returns it as a String.
-    fn format_high_level(&self) -> String {
+    fn format_high_level(&self) -> Result<String> {
         // Get the high-level summary only if `resctl-bench` supports
-        // that option (available since v2.2.3)
-        let resctl_bench_version = BenchVersion::new(&self.version);
-        if VersionReq::parse("<2.2.3")
-            .unwrap()
-            .matches(&resctl_bench_version.semver)
-        {
-            return String::new();
+        // that option
+        if !BenchVersion::new(&self.version).high_level {
+            return Ok(String::new());
         }
 
         let path = merged_file(&self.version, &self.model_name, None);
-        BenchMerge::do_merge(
+        BenchMerge::merge_directory(
             &self.version,
             &database_directory(&self.version, &self.model_name),
             &path,
+            0,
         )
-        .expect("Failed to do the merge for obtaining high level summary");
+        .context("Failed to do the merge for obtaining high level summary")?
+        .format("iocost-tune:high-level")
+        .context("Failed to format high level")
+    }
+}
 
-        run_resctl(
-            &self.version,
-            &[
-                "--result",
-                &path.to_string_lossy(),
-                "format",
-                "iocost-tune:high-level",
-            ],
-        )
-        .expect("Failed to run resctl-bench to format high level")
+/// Import step in which an error occurred
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ImportStage {
+    Fetch,
+    Parse,
+    Quality,
+    Validate,
+    Render,
+    Link,
+    Commit,
+}
+
+/// An error found while importing a result file
+#[derive(Debug, Serialize)]
+struct ImportError {
+    /// URL or path of the result file, if the error is specific to one
+    link: Option<String>,
+    stage: ImportStage,
+    error: String,
+}
+
+impl ImportError {
+    fn new(link: Option<&str>, stage: ImportStage, error: anyhow::Error) -> Self {
+        ImportError {
+            link: link.map(str::to_string),
+            stage,
+            error: format!("{:#}", error),
+        }
     }
 }
 
-async fn run_as_gh_workflow(database_path: &str) -> Result<()>{
-    let envvar_contents = std::env::var(GH_CONTEXT_ENVVAR)
-        .context(format!("Can't read environment variable {}", GH_CONTEXT_ENVVAR))?;
-    let context = json::parse(&envvar_contents)?;
-    let issue_id = context["event"]["issue"]["number"].as_u64().unwrap();
-    let git_repo = git2::Repository::open(".")?;
-    let mut index = git_repo.index()?;
-    // HashMap to keep the complete set of results
-    let mut merged = HashMap::new();
+impl std::fmt::Display for ImportStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let stage = match self {
+            ImportStage::Fetch => "fetch",
+            ImportStage::Parse => "parse",
+            ImportStage::Quality => "quality check",
+            ImportStage::Validate => "validation",
+            ImportStage::Render => "render",
+            ImportStage::Link => "duplicate linking",
+            ImportStage::Commit => "commit",
+        };
+        write!(f, "{}", stage)
+    }
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.link {
+            Some(link) => write!(f, "File {} failed {}: \n\n{}", link, self.stage, self.error),
+            None => write!(f, "Import failed at {}: \n\n{}", self.stage, self.error),
+        }
+    }
+}
+
+/// The quality rules a result file failed
+#[derive(Debug, Serialize)]
+struct QualityReport {
+    /// URL or path of the result file
+    link: String,
+    findings: Vec<Finding>,
+}
+
+/// A result file skipped because it's identical to another one of the
+/// same submission
+#[derive(Debug, Serialize)]
+struct SkippedResult {
+    /// URL or path of the skipped result file
+    link: String,
+    /// URL or path of the identical result file
+    same_as: String,
+}
+
+/// What came out of an import: the errors which aborted it, if any, the
+/// quality findings of the result files, whether or not they were
+/// imported, and the result files submitted more than once.
+#[derive(Debug, Default, Serialize)]
+struct ImportOutcome {
+    errors: Vec<ImportError>,
+    quality: Vec<QualityReport>,
+    skipped: Vec<SkippedResult>,
+}
+
+/// Formats `report` as a markdown table followed by the hints to fix
+/// the findings.
+fn format_quality_report(report: &QualityReport) -> String {
+    let mut out = format!("**{}**\n\n| | Rule | Finding |\n|---|---|---|\n", report.link);
+    let mut hints: Vec<&str> = vec![];
+    for finding in &report.findings {
+        out.push_str(&format!(
+            "| {} | `{}` | {} |\n",
+            finding.severity,
+            finding.rule,
+            finding.message.replace('|', "\\|")
+        ));
+        if !hints.contains(&finding.hint.as_str()) {
+            hints.push(&finding.hint);
+        }
+    }
+    out.push('\n');
+    for hint in hints {
+        out.push_str(&format!("- {}\n", hint));
+    }
+    out
+}
+
+/// Formats `outcome` as a single Github comment. Returns `None` if
+/// there's nothing to report.
+fn format_import_outcome(outcome: &ImportOutcome) -> Option<String> {
+    if outcome.errors.is_empty() && outcome.quality.is_empty() && outcome.skipped.is_empty() {
+        return None;
+    }
+    let mut comment = if outcome.errors.is_empty() {
+        String::from("The results were imported with warnings.\n\n")
+    } else {
+        String::from("Import aborted, no result was added to the database.\n\n")
+    };
+    if !outcome.quality.is_empty() {
+        comment.push_str("### Quality check\n\n");
+        comment.push_str(
+            &outcome
+                .quality
+                .iter()
+                .map(format_quality_report)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        );
+    }
+    // Quality check errors are detailed above.
+    let errors: Vec<String> = outcome
+        .errors
+        .iter()
+        .filter(|e| !matches!(e.stage, ImportStage::Quality))
+        .map(|e| e.to_string())
+        .collect();
+    if !errors.is_empty() {
+        if !outcome.quality.is_empty() {
+            comment.push_str("\n### Errors\n\n");
+        }
+        comment.push_str(&errors.join("\n\n"));
+    }
+    if !outcome.skipped.is_empty() {
+        comment.push_str("\n### Skipped\n\n");
+        for skipped in &outcome.skipped {
+            comment.push_str(&format!(
+                "- {} is identical to {}\n",
+                skipped.link, skipped.same_as
+            ));
+        }
+    }
+    Some(comment)
+}
+
+/// Formats `error`, which stopped the import before it could report an
+/// outcome, as a Github comment.
+fn format_import_failure(error: &anyhow::Error) -> String {
+    format!("Import failed, no result was added to the database.\n\n{:#}", error)
+}
+
+/// A set of result files imported as a whole. The files are fetched,
+/// validated and rendered in a staging directory and only moved into
+/// the database once all of them passed, so that a failure leaves the
+/// database untouched.
+struct ImportTransaction {
+    staging: tempfile::TempDir,
+    results: Vec<BenchResult>,
+    /// Results in the database which are submitted again, with the
+    /// issues linked to them
+    duplicates: HashMap<PathBuf, BenchResult>,
+    /// Fingerprints of the results in the database, mapped to their
+    /// result file
+    fingerprints: HashMap<String, PathBuf>,
+    /// Fingerprints of the result files of this submission, mapped to
+    /// their link
+    links: HashMap<String, String>,
+    quality_policy: QualityPolicy,
+    outcome: ImportOutcome,
+}
+
+/// Changes made to the working tree by `ImportTransaction::apply()`,
+/// kept to undo them if the import can't be committed.
+#[derive(Default)]
+struct AppliedImport {
+    /// Files moved into the database, with the backup of the file they
+    /// replaced, if any
+    files: Vec<(PathBuf, Option<PathBuf>)>,
+    /// Directories created in the database
+    dirs: Vec<PathBuf>,
+}
+
+impl AppliedImport {
+    /// Restores the working tree as it was before the import.
+    fn rollback(self) -> Result<()> {
+        for (path, backup) in self.files.into_iter().rev() {
+            match backup {
+                Some(backup) => fs::rename(backup, &path)?,
+                None => fs::remove_file(&path)?,
+            }
+        }
+        for dir in self.dirs.into_iter().rev() {
+            fs::remove_dir(dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the fingerprint saved in the metadata file of the result
+/// file in `path`, if any.
+fn metadata_fingerprint(path: &Path) -> Option<String> {
+    let metadata_path = path.with_extension("").with_extension("json.metadata");
+    fs::read_to_string(metadata_path)
+        .ok()
+        .and_then(|metadata| serde_json::from_str::<BenchResult>(&metadata).ok())
+        .and_then(|metadata| metadata.fingerprint)
+}
+
+/// Returns the fingerprints of the results in the database, mapped to
+/// their result file. Fingerprints are read from the metadata files
+/// when available, otherwise they are computed from the results, see
+/// `backfill_fingerprints()`. Results which can't be read are skipped.
+fn database_fingerprints(database_path: &str) -> Result<HashMap<String, PathBuf>> {
+    let mut fingerprints = HashMap::new();
+    for path in glob(&format!("{}/*/*/result-*.json.gz", database_path))?.flatten() {
+        let fingerprint = match metadata_fingerprint(&path) {
+            Some(fingerprint) => fingerprint,
+            None => match load_json(&path.to_string_lossy()) {
+                Ok(result) => result_fingerprint(&result),
+                Err(e) => {
+                    println!("Warning: skipping unreadable {}: {:#}", path.display(), e);
+                    continue;
+                }
+            },
+        };
+        fingerprints.insert(fingerprint, path);
+    }
+    Ok(fingerprints)
+}
+
+/// Saves the fingerprint in the metadata file of the results in the
+/// database which don't have one yet, creating the missing metadata
+/// files. This is a one-off maintenance step, the changes are left to
+/// be committed separately.
+async fn backfill_fingerprints(database_path: &str) -> Result<()> {
+    let mut nr_updated = 0;
+    let mut nr_failed = 0;
+    for path in glob(&format!("{}/*/*/result-*.json.gz", database_path))?.flatten() {
+        if metadata_fingerprint(&path).is_some() {
+            continue;
+        }
+        let metadata = match BenchResult::load(&path, database_path).await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                println!("Warning: skipping unreadable {}: {:#}", path.display(), e);
+                nr_failed += 1;
+                continue;
+            }
+        };
+        fs::write(metadata.metadata_file_path(), serde_json::to_string(&metadata)?)?;
+        nr_updated += 1;
+    }
+    println!("Added the fingerprint to {} metadata files", nr_updated);
+    if nr_failed > 0 {
+        bail!("{} result files couldn't be read", nr_failed);
+    }
+    Ok(())
+}
 
-    // Download and validate all provided URLs.
-    let urls = get_urls(&context)?;
-    let mut errors = vec![];
-    for url in urls {
-        // Download resctl-bench result, process and validate it,
-        // and add it to the database and the repo
-        let path = download_url(&url).await?;
-        let mut result = BenchResult::new(&path, database_path).await?;
+impl ImportTransaction {
+    fn new(database_path: &str, quality_policy: &QualityPolicy) -> Result<Self> {
+        // Stage in the current directory so that files can be renamed
+        // into the database.
+        let staging = tempfile::Builder::new()
+            .prefix(".import-staging-")
+            .tempdir_in(".")
+            .context("Creating staging directory")?;
+        fs::create_dir(staging.path().join("pdfs"))?;
+        fs::create_dir(staging.path().join("backup"))?;
+        Ok(ImportTransaction {
+            staging,
+            results: vec![],
+            duplicates: HashMap::new(),
+            fingerprints: database_fingerprints(database_path)?,
+            links: HashMap::new(),
+            quality_policy: quality_policy.clone(),
+            outcome: Default::default(),
+        })
+    }
+
+    /// Fetches the result file in `link` into the staging directory,
+    /// checks its quality, validates it and renders its pdf. If the same
+    /// result is already in the database, `issue_id` is linked to it
+    /// instead. If it was already submitted in this transaction, it's
+    /// skipped. Errors, quality findings and skipped results are
+    /// recorded in `self.outcome`.
+    async fn stage(
+        &mut self,
+        link: &str,
+        mirror: Option<&Path>,
+        database_path: &str,
+        issue_id: u64,
+    ) {
+        let path = match fetch_result(link, mirror, self.staging.path()).await {
+            Ok(path) => path,
+            Err(e) => return self.outcome.errors.push(ImportError::new(Some(link), ImportStage::Fetch, e)),
+        };
+        let mut result = match BenchResult::new(&path, database_path).await {
+            Ok(result) => result,
+            Err(e) => return self.outcome.errors.push(ImportError::new(Some(link), ImportStage::Parse, e)),
+        };
         result.issue = Some(issue_id);
-        result.url = Some(url.clone());
+        result.url = Some(link.to_string());
+
+        let fingerprint = result.fingerprint.clone().unwrap();
+        if let Some(same_as) = self.links.get(&fingerprint) {
+            println!("{} is identical to {}, skipping", link, same_as);
+            return self.outcome.skipped.push(SkippedResult {
+                link: link.to_string(),
+                same_as: same_as.clone(),
+            });
+        }
+        self.links.insert(fingerprint.clone(), link.to_string());
+        if let Some(original) = self.fingerprints.get(&fingerprint) {
+            println!("{} duplicates {}", link, original.display());
+            if let Err(e) = self.link_duplicate(&original.clone(), database_path, issue_id).await {
+                self.outcome.errors.push(ImportError::new(Some(link), ImportStage::Link, e));
+            }
+            return;
+        }
+
+        match result.check_quality(&self.quality_policy) {
+            Ok(findings) => {
+                let rejected: Vec<&str> = findings
+                    .iter()
+                    .filter(|f| f.severity == Severity::Reject)
+                    .map(|f| f.rule)
+                    .collect();
+                if !findings.is_empty() {
+                    self.outcome.quality.push(QualityReport {
+                        link: link.to_string(),
+                        findings,
+                    });
+                }
+                if !rejected.is_empty() {
+                    let e = anyhow!("Rejected by {}", rejected.join(", "));
+                    return self.outcome.errors.push(ImportError::new(Some(link), ImportStage::Quality, e));
+                }
+            }
+            Err(e) => return self.outcome.errors.push(ImportError::new(Some(link), ImportStage::Quality, e)),
+        }
+
         if let Err(e) = result.validate() {
-            errors.push(
-                format!("File {} failed validation: \n\n{}", url, e)
-            );
-            continue;
+            return self.outcome.errors.push(ImportError::new(Some(link), ImportStage::Validate, e));
+        }
+        let pdfs_dir = self.staging.path().join("pdfs");
+        if let Err(e) = save_pdf_to(&result.version, Path::new(&result.result_file), &pdfs_dir, None) {
+            return self.outcome.errors.push(ImportError::new(Some(link), ImportStage::Render, e));
+        }
+        self.results.push(result);
+    }
+
+    /// Records `issue_id` as a duplicate submission of the result file
+    /// `original` in the database.
+    async fn link_duplicate(&mut self, original: &Path, database_path: &str, issue_id: u64) -> Result<()> {
+        if !self.duplicates.contains_key(original) {
+            let metadata = BenchResult::load(original, database_path).await?;
+            self.duplicates.insert(original.to_path_buf(), metadata);
+        }
+        let metadata = self.duplicates.get_mut(original).unwrap();
+        if metadata.issue != Some(issue_id) && !metadata.duplicate_issues.contains(&issue_id) {
+            metadata.duplicate_issues.push(issue_id);
+        }
+        Ok(())
+    }
+
+    /// Moves `src` to `dst` in the database, backing up and recording
+    /// what's needed to undo it in `applied`.
+    fn install(&self, src: &Path, dst: &Path, applied: &mut AppliedImport) -> Result<()> {
+        let backup = if dst.exists() {
+            let backup = self
+                .staging
+                .path()
+                .join("backup")
+                .join(applied.files.len().to_string());
+            fs::rename(dst, &backup)?;
+            Some(backup)
+        } else {
+            None
+        };
+        let ret = fs::rename(src, dst);
+        applied.files.push((dst.to_path_buf(), backup));
+        Ok(ret?)
+    }
+
+    /// Moves the staged result files and their metadata into the
+    /// database and adds them to `index`. On failure, the database is
+    /// restored before returning the error.
+    fn apply(&self, index: &mut git2::Index) -> Result<AppliedImport> {
+        let mut applied = AppliedImport::default();
+        match self.try_apply(index, &mut applied) {
+            Ok(()) => Ok(applied),
+            Err(e) => {
+                applied.rollback().context("Failed to roll back the import")?;
+                Err(e)
+            }
+        }
+    }
+
+    fn try_apply(&self, index: &mut git2::Index, applied: &mut AppliedImport) -> Result<()> {
+        for result in &self.results {
+            // Generate DB directory and place the result file there
+            let mut new_dirs = vec![];
+            for dir in Path::new(&result.dir).ancestors() {
+                if dir.as_os_str().is_empty() || dir.exists() {
+                    break;
+                }
+                new_dirs.push(dir.to_path_buf());
+            }
+            fs::create_dir_all(&result.dir)?;
+            applied.dirs.extend(new_dirs.into_iter().rev());
+
+            // Create metadata file next to the staged result
+            let metadata_file = self
+                .staging
+                .path()
+                .join(result.metadata_file_path().file_name().unwrap());
+            fs::write(&metadata_file, serde_json::to_string(result)?)?;
+
+            self.install(Path::new(&result.result_file), &result.db_file(), applied)?;
+            self.install(&metadata_file, &result.metadata_file_path(), applied)?;
+            index.add_path(&result.db_file())?;
+            index.add_path(&result.metadata_file_path())?;
+        }
+        for metadata in self.duplicates.values() {
+            let metadata_file = self
+                .staging
+                .path()
+                .join(metadata.metadata_file_path().file_name().unwrap());
+            fs::write(&metadata_file, serde_json::to_string(metadata)?)?;
+            self.install(&metadata_file, &metadata.metadata_file_path(), applied)?;
+            index.add_path(&metadata.metadata_file_path())?;
+        }
+        Ok(())
+    }
+
+    /// Moves the rendered pdfs to `pdfs_dir`.
+    fn save_pdfs(&self, pdfs_dir: &Path) -> Result<()> {
+        fs::create_dir_all(pdfs_dir)?;
+        for pdf in fs::read_dir(self.staging.path().join("pdfs"))? {
+            let pdf = pdf?;
+            fs::rename(pdf.path(), pdfs_dir.join(pdf.file_name()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Fetches and validates the result files in `links`, then adds all of
+/// them to the database and commits them to a new
+/// iocost-bot/<issue_id> branch. If a `mirror` directory is specified,
+/// URLs are resolved against it instead of being downloaded. Results
+/// failing a reject rule of `quality_policy` are errors. If any error is
+/// found, nothing is imported. The errors and the quality findings are
+/// returned.
+async fn import_results(
+    database_path: &str,
+    issue_id: u64,
+    links: &[String],
+    mirror: Option<&Path>,
+    quality_policy: &QualityPolicy,
+) -> Result<ImportOutcome> {
+    let git_repo = git2::Repository::open(".")?;
+    let mut index = git_repo.index()?;
+
+    // Fetch and validate all provided links.
+    let mut transaction = ImportTransaction::new(database_path, quality_policy)?;
+    for link in links {
+        transaction.stage(link, mirror, database_path, issue_id).await;
+    }
+    if !transaction.outcome.errors.is_empty() {
+        return Ok(transaction.outcome);
+    }
+    if transaction.results.is_empty() && transaction.duplicates.is_empty() {
+        println!("Found no new results files to merge...");
+        return Ok(transaction.outcome);
+    }
+
+    let applied = match transaction.apply(&mut index) {
+        Ok(applied) => applied,
+        Err(e) => {
+            let mut outcome = transaction.outcome;
+            outcome.errors.push(ImportError::new(None, ImportStage::Commit, e));
+            return Ok(outcome);
         }
-        result.add_to_database(Some(&issue_id.to_string()))?;
-        index.add_path(&result.db_file())?;
-        index.add_path(&result.metadata_file_path())?;
+    };
+    if let Err(e) = commit_import(&git_repo, &mut index, issue_id, &transaction) {
+        applied.rollback().context("Failed to roll back the import")?;
+        let mut outcome = transaction.outcome;
+        outcome.errors.push(ImportError::new(None, ImportStage::Commit, e));
+        return Ok(outcome);
+    }
+
+    transaction.save_pdfs(&PathBuf::from(".").join(format!("pdfs-for-{}", issue_id)))?;
+    Ok(transaction.outcome)
+}
+
+/// Commits the files in `index` to a new iocost-bot/<issue_id> branch
+/// with a summary of the results imported by `transaction`.
+fn commit_import(
+    git_repo: &git2::Repository,
+    index: &mut git2::Index,
+    issue_id: u64,
+    transaction: &ImportTransaction,
+) -> Result<()> {
+    // HashMap to keep the complete set of results
+    let mut merged = HashMap::new();
+    for result in &transaction.results {
         merged
             .entry(format!("{}-{}", &result.version, &result.model_name))
             .or_insert_with(|| HighLevel::new(&result.version, &result.model_name))
             .increment();
     }
 
-    if !errors.is_empty() {
-        octocrab::OctocrabBuilder::new()
-            .personal_token(context["token"].as_str().unwrap().to_string())
-            .build()?
-            .issues(
-                context["repository_owner"].as_str().unwrap(),
-                "iocost-benchmarks",
-            )
-            .create_comment(issue_id, errors.join("\n\n"))
-            .await?;
+    let mut summaries = vec![];
+    for v in merged.values() {
+        summaries.push(format!(
+            "[{} ({})] {} new files\n{}",
+            v.model_name,
+            v.version,
+            v.new_files,
+            v.format_high_level()?
+        ));
     }
-    if merged.is_empty() {
-        println!("Found no new results files to merge...");
-        return Ok(());
+    for (path, metadata) in &transaction.duplicates {
+        summaries.push(format!(
+            "[{} ({})] duplicate of {}",
+            metadata.model_name,
+            metadata.version,
+            path.display()
+        ));
     }
 
     // Commit the new and changed files.
@@ -304,25 +909,13 @@ async fn run_as_gh_workflow(database_path: &str) -> Result<()>{
     let parent_commit = git_repo.head()?.peel_to_commit()?;
     let oid = index.write_tree()?;
     let tree = git_repo.find_tree(oid)?;
-    let description = format!(
-        "Closes #{}\n\n{}",
-        issue_id,
-        merged
-            .iter()
-            .map(|(_, v)| format!(
-                "[{} ({})] {} new files\n{}",
-                v.model_name,
-                v.version,
-                v.new_files,
-                v.format_high_level()
-            ))
-            .collect::<Vec<String>>()
-            .join("\n")
-    );
+    let description = format!("Closes #{}\n\n{}", issue_id, summaries.join("\n"));
     let commit_title = format!("Automated update from issue {}", issue_id);
     let commit_message = format!("{commit_title}\n\n{description}");
+    // HEAD is left alone so that a failure doesn't leave it pointing
+    // at a commit without a branch. The branch is created last.
     let commit = git_repo.commit(
-        Some("HEAD"),
+        None,
         &sig,
         &sig,
         &commit_message,
@@ -331,22 +924,78 @@ async fn run_as_gh_workflow(database_path: &str) -> Result<()>{
     )?;
     let branch_name = format!("iocost-bot/{}", issue_id);
     git_repo.branch(&branch_name, &git_repo.find_commit(commit)?, true)?;
+    Ok(())
+}
+
+async fn run_as_gh_workflow(database_path: &str, quality_policy: &QualityPolicy) -> Result<()>{
+    let envvar_contents = std::env::var(GH_CONTEXT_ENVVAR)
+        .context(format!("Can't read environment variable {}", GH_CONTEXT_ENVVAR))?;
+    let context = json::parse(&envvar_contents)?;
+    let issue_id = context["event"]["issue"]["number"].as_u64().unwrap();
+
+    let outcome = match get_urls(&context) {
+        Ok(urls) => import_results(database_path, issue_id, &urls, None, quality_policy).await,
+        Err(e) => Err(e),
+    };
+
+    // Report errors which stopped the import too, they'd only show up in
+    // the workflow log otherwise.
+    let comment = match &outcome {
+        Ok(outcome) => format_import_outcome(outcome),
+        Err(e) => Some(format_import_failure(e)),
+    };
+    if let Some(comment) = comment {
+        octocrab::OctocrabBuilder::new()
+            .personal_token(context["token"].as_str().unwrap().to_string())
+            .build()?
+            .issues(
+                context["repository_owner"].as_str().unwrap(),
+                "iocost-benchmarks",
+            )
+            .create_comment(issue_id, comment)
+            .await?;
+    }
+    outcome?;
 
     // The rest of the process happens in the workflow.
     Ok(())
 }
 
+/// Runs the same import as the Github workflow with the links read
+/// from local files, writing the errors and quality findings to
+/// `report` as JSON instead of commenting on the issue.
+async fn run_locally(database_path: &str, args: &Cli, quality_policy: &QualityPolicy) -> Result<()> {
+    let mut issue_id = None;
+    let mut links = vec![];
+    if let Some(path) = &args.issue {
+        let (number, urls) = get_issue_file_urls(path)?;
+        issue_id = number;
+        links.extend(urls);
+    }
+    if let Some(path) = &args.list {
+        links.extend(get_list_file_links(path)?);
+    }
+    let issue_id = match args.issue_number.or(issue_id) {
+        Some(id) => id,
+        None => bail!("No issue number found, specify one with --issue-number"),
+    };
 
-/// Top-level struct to parse the config toml file
-#[derive(Debug, Deserialize)]
-struct TomlData {
-    config: Config,
-}
+    let mirror = args.mirror.as_ref().map(PathBuf::from);
+    let outcome =
+        import_results(database_path, issue_id, &links, mirror.as_deref(), quality_policy).await?;
 
-/// Struct to parse the [config] section of the config toml file
-#[derive(Debug, Deserialize)]
-struct Config {
-    database_dir: Option<String>,
+    if let Some(comment) = format_import_outcome(&outcome) {
+        let report = serde_json::json!({
+            "issue": issue_id,
+            "errors": outcome.errors,
+            "quality": outcome.quality,
+            "skipped": outcome.skipped,
+        });
+        fs::write(&args.report, serde_json::to_string_pretty(&report)?)?;
+        println!("{}", comment);
+        println!("Errors or warnings found, see {}", args.report);
+    }
+    Ok(())
 }
 
 #[derive(Parser, Debug)]
@@ -355,7 +1004,8 @@ struct Config {
 ///
 /// By default, it runs as part of a Github workflow, reading the input
 /// from an environment variable (GITHUB_CONTEXT). Run with -r
-/// (--result) to specify a local result file.
+/// (--result) to specify a local result file, or with --issue and/or
+/// --list to run the workflow import locally.
 struct Cli {
     /// Path of the toml config file to load
     #[arg(short, long, value_name = "FILE")]
@@ -368,6 +1018,36 @@ struct Cli {
     /// Output database dir
     #[arg(short, long, value_name = "DIR", default_value = "database")]
     database_dir: Option<String>,
+
+    /// Saved Github issue or comment JSON (or GITHUB_CONTEXT) to import
+    /// the results linked in
+    #[arg(long, value_name = "FILE.json", conflicts_with = "result")]
+    issue: Option<String>,
+
+    /// File listing result file URLs or local paths to import, one per
+    /// line
+    #[arg(long, value_name = "FILE", conflicts_with = "result")]
+    list: Option<String>,
+
+    /// Directory to look up result file URLs in, as <DIR>/<host>/<path>,
+    /// instead of downloading them
+    #[arg(long, value_name = "DIR")]
+    mirror: Option<String>,
+
+    /// Issue number to use for the commit and the branch name, overrides
+    /// the one in --issue
+    #[arg(long, value_name = "NUMBER")]
+    issue_number: Option<u64>,
+
+    /// File to write the import errors, quality findings and skipped
+    /// results to, as JSON, when running locally
+    #[arg(long, value_name = "FILE", default_value = "import-errors.json")]
+    report: String,
+
+    /// Save the fingerprint in the metadata of the results in the
+    /// database which don't have one yet, then exit without importing
+    #[arg(long, conflicts_with_all = ["result", "issue", "list"])]
+    backfill_fingerprints: bool,
 }
 
 #[tokio::main]
@@ -375,15 +1055,12 @@ async fn main() -> Result<()> {
     let args = Cli::parse();
 
     // Load config from toml file, if specified
-    let config: Option<TomlData> = match args.config_file {
+    let config: Option<TomlData> = match &args.config_file {
         Some(path) => {
-            match fs::read_to_string(&path) {
-                Ok(contents) => {
-                    toml::from_str(&contents)
-                        .expect(&format!("Error parsing toml file {}", &path))
-                },
-                Err(_) => {
-                    eprintln!("Can't open config file: {}", &path);
+            match load_config(path) {
+                Ok(config) => Some(config),
+                Err(e) => {
+                    eprintln!("{:#}", e);
                     exit(1);
                 }
             }
@@ -393,24 +1070,44 @@ async fn main() -> Result<()> {
 
     // Process general parameters
     let database_dir;
+    let quality_policy;
     if let Some(config) = config {
-        database_dir = config.config.database_dir.unwrap_or(args.database_dir.unwrap());
+        database_dir = config.config.database_dir.unwrap_or(args.database_dir.clone().unwrap());
+        set_version_registry(config.versions);
+        quality_policy = config.quality;
     } else {
-        database_dir = args.database_dir.unwrap()
+        database_dir = args.database_dir.clone().unwrap();
+        quality_policy = QualityPolicy::default();
     }
 
-    if let Some(result_file) = args.result {
+    if args.backfill_fingerprints {
+        return backfill_fingerprints(&database_dir).await;
+    }
+
+    if args.issue.is_some() || args.list.is_some() {
+        // Run the workflow import with local input
+        return run_locally(&database_dir, &args, &quality_policy).await;
+    }
+
+    if let Some(result_file) = &args.result {
         // Run with result file as input
         let bench_result = BenchResult::new(
-            &result_file,
+            result_file,
             &database_dir).await?;
+        let findings = bench_result.check_quality(&quality_policy)?;
+        for finding in &findings {
+            println!("{}", finding);
+        }
+        if findings.iter().any(|f| f.severity == Severity::Reject) {
+            bail!("File {} failed the quality check", &result_file);
+        }
         bench_result.validate()
-            .expect(&format!("File {} failed validation", &result_file));
+            .unwrap_or_else(|_| panic!("File {} failed validation", &result_file));
         return bench_result.add_to_database(None);
     } else {
         // Run as part of a Github workflow
         println!("No result file specified: reading result info from \
                   Github workflow ({} envvar)", GH_CONTEXT_ENVVAR);
-        return run_as_gh_workflow(&database_dir).await;
+        return run_as_gh_workflow(&database_dir, &quality_policy).await;
     }
 }
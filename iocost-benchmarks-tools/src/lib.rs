@@ -0,0 +1,4 @@
+//! Code shared by the iocost-benchmarks binaries.
+pub mod common;
+pub mod fwrev;
+pub mod quality;
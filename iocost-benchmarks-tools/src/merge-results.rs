@@ -1,19 +1,30 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use clap::Parser;
 use dashmap::DashMap;
 use glob::glob;
 use rayon::prelude::*;
+use resctl_bench::hwdb::{self, HwdbMatch};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::io::Write;
-use std::{fs, path::PathBuf};
+use std::process::exit;
+use std::{fs, path::Path, path::PathBuf};
 
-use crate::common::BenchMerge;
+use crate::cache::MergeCache;
+use iocost_benchmarks::common::{
+    database_directory, load_config, set_version_registry, source_date, BenchMerge, BenchVersion,
+    version_key, Policy, ResultInfo, TomlData,
+};
 
-mod common;
+mod cache;
+mod compare;
+mod udev;
 
 /// Writes a hwdb header in `hwdb_file` containing data information and
 /// a reference to `commit_id`
 fn write_hwdb_file_header(hwdb_file: &mut fs::File, commit_id: Option<String>) -> Result<()> {
     let mut hwdb_text = format!("# This file was auto-generated on {}.\n",
-        chrono::Utc::now().to_rfc2822());
+        source_date().to_rfc2822());
     if let Some(id) = commit_id {
         hwdb_text.push_str(&format!(r#"# From the following commit:
 # https://github.com/iocost-benchmark/iocost-benchmarks/commit/{}
@@ -22,14 +33,266 @@ fn write_hwdb_file_header(hwdb_file: &mut fs::File, commit_id: Option<String>) -
     hwdb_text.push_str(r#"#
 # Match key format:
 # block:<devpath>:name:<model name>:fwrev:<firmware revision>:
+# with "*", "?", "[", "]" and ":" in model names and firmware revisions
+# replaced with "?".
+#
+# Entries with a "low confidence" comment list solutions which vary a
+# lot with the benchmark runs. More results are needed for these drives.
 
 "#);
     writeln!(hwdb_file, "{}", hwdb_text)?;
     Ok(())
 }
 
+/// Firmware-specific merge in the database manifest
+#[derive(Serialize)]
+struct ManifestFWMerge<'a> {
+    fwrev: &'a str,
+    data_points: usize,
+    /// Solutions flagged as low confidence, more results are needed
+    low_confidence: &'a [String],
+}
+
+/// Database manifest entry for a model and version
+#[derive(Serialize)]
+struct ManifestEntry<'a> {
+    results: &'a [ResultInfo],
+    fwrevs: BTreeSet<&'a str>,
+    kernel_versions: BTreeSet<&'a str>,
+    issues: BTreeSet<u64>,
+    data_points: usize,
+    /// Solutions flagged as low confidence, more results are needed
+    low_confidence: &'a [String],
+    /// fwrev-specific merges, the latest fwrev first
+    fwrev_merges: Vec<ManifestFWMerge<'a>>,
+    /// This merge was selected for the final hwdb file
+    chosen: bool,
+}
+
+impl<'a> ManifestEntry<'a> {
+    fn new(merge: &'a BenchMerge, chosen: bool) -> Self {
+        ManifestEntry {
+            results: &merge.results,
+            fwrevs: merge.results.iter().map(|r| r.fwrev.as_str()).collect(),
+            kernel_versions: merge.results.iter().map(|r| r.kernel_version.as_str()).collect(),
+            issues: merge.results.iter().filter_map(|r| r.issue).collect(),
+            data_points: merge.data_points,
+            low_confidence: &merge.low_confidence,
+            fwrev_merges: merge
+                .fwmerges
+                .iter()
+                .map(|fwmerge| ManifestFWMerge {
+                    fwrev: &fwmerge.fwrev,
+                    data_points: fwmerge.data_points,
+                    low_confidence: &fwmerge.low_confidence,
+                })
+                .collect(),
+            chosen,
+        }
+    }
+}
+
+/// Merge of a model selected for the final hwdb file by the policy
+struct Selection<'a> {
+    merge: Option<&'a BenchMerge>,
+    /// fwrevs whose specific solutions aren't used
+    excluded_fwrevs: Vec<&'a str>,
+    /// Why each merge was selected or rejected
+    explanation: Vec<String>,
+}
+
+/// Selects the merge of `model` to use in the final hwdb file among
+/// `alternatives`, one per resctl-bench version, according to `policy`.
+fn select_merge<'a>(
+    policy: &Policy,
+    model: &str,
+    alternatives: &'a [BenchMerge],
+) -> Result<Selection<'a>> {
+    let model_policy = policy.models.get(model);
+    let excluded_versions: Vec<&String> = policy
+        .exclude_versions
+        .iter()
+        .chain(model_policy.iter().flat_map(|p| &p.exclude_versions))
+        .collect();
+    let excluded_fwrevs: Vec<&String> = policy
+        .exclude_fwrevs
+        .iter()
+        .chain(model_policy.iter().flat_map(|p| &p.exclude_fwrevs))
+        .collect();
+    let min_data_points = model_policy
+        .and_then(|p| p.min_data_points)
+        .unwrap_or(policy.min_data_points);
+    let prefer_newest = model_policy
+        .and_then(|p| p.prefer_newest)
+        .unwrap_or(policy.prefer_newest);
+
+    let mut explanation = vec![];
+    let merge = match model_policy.and_then(|p| p.pin.as_ref()) {
+        Some(pin) if excluded_versions.contains(&pin) => {
+            bail!("Model {} is pinned to version {} which is excluded", model, pin)
+        }
+        Some(pin) => match alternatives.iter().find(|m| &m.version_str == pin) {
+            Some(merge) if merge.hwdb.is_none() => {
+                bail!("Model {} is pinned to version {} which has no hwdb support", model, pin)
+            }
+            Some(merge) => {
+                explanation.push(format!("{}: pinned", pin));
+                Some(merge)
+            }
+            None => bail!("Model {} is pinned to missing version {}", model, pin),
+        },
+        None => {
+            let mut candidates = vec![];
+            for merge in alternatives {
+                if merge.hwdb.is_none() {
+                    explanation.push(format!(
+                        "{}: rejected, no hwdb support",
+                        merge.version_str
+                    ));
+                } else if excluded_versions.contains(&&merge.version_str) {
+                    explanation.push(format!("{}: rejected, version excluded", merge.version_str));
+                } else if merge.data_points < min_data_points {
+                    explanation.push(format!(
+                        "{}: rejected, {} data points is less than {}",
+                        merge.version_str, merge.data_points, min_data_points
+                    ));
+                } else {
+                    candidates.push(merge);
+                }
+            }
+            let merge = if prefer_newest {
+                candidates.into_iter().max_by_key(|m| version_key(&m.version_str))
+            } else {
+                candidates.into_iter().max_by_key(|m| m.data_points)
+            };
+            if let Some(merge) = merge {
+                explanation.push(format!(
+                    "{}: selected, {} ({} data points)",
+                    merge.version_str,
+                    if prefer_newest { "newest version" } else { "most data points" },
+                    merge.data_points
+                ));
+            }
+            merge
+        }
+    };
+
+    let mut excluded = vec![];
+    for fwmerge in merge.iter().flat_map(|m| &m.fwmerges) {
+        if excluded_fwrevs.contains(&&fwmerge.fwrev) {
+            explanation.push(format!("fwrev {}: specific solution excluded", fwmerge.fwrev));
+            excluded.push(fwmerge.fwrev.as_str());
+        }
+    }
+
+    Ok(Selection {
+        merge,
+        excluded_fwrevs: excluded,
+        explanation,
+    })
+}
+
+/// Merges the results of `model_name` for resctl-bench `version` with
+/// the fwrev-specific merges configured in `policy` and `bootstrap`
+/// resamples and saves the pdf in `pdfs_dir`. If the inputs didn't
+/// change since the
+/// merge saved in `cache`, and `force` isn't set, the cached merge and
+/// pdf are used instead.
+fn merge_model(
+    cache: &MergeCache,
+    version: &str,
+    model_name: &str,
+    policy: &Policy,
+    pdfs_dir: &Path,
+    bootstrap: u32,
+    force: bool,
+) -> Result<BenchMerge> {
+    let fwmerge_opts = policy.fwmerge_opts(model_name);
+    let inputs = BenchMerge::result_paths_for(&database_directory(version, model_name))?;
+    let key = MergeCache::key(
+        &BenchVersion::new(version),
+        &inputs,
+        &fwmerge_opts,
+        bootstrap,
+    )?;
+
+    if !force {
+        if let Some(merge) = cache.load(version, model_name, &key)? {
+            println!("Inputs of {} {} unchanged, using cached merge", version, model_name);
+            fs::create_dir_all(pdfs_dir)?;
+            fs::copy(
+                cache.pdf_path(version, model_name),
+                pdfs_dir.join(merge.build_descriptive_filename("pdf", None)),
+            )?;
+            return Ok(merge);
+        }
+    }
+
+    let merge = BenchMerge::merge(
+        version.to_string(),
+        model_name.to_string(),
+        &fwmerge_opts,
+        bootstrap,
+    )?;
+    merge.save_pdf_in(pdfs_dir)?;
+    cache.store(
+        &merge,
+        &key,
+        &pdfs_dir.join(merge.build_descriptive_filename("pdf", None)),
+    )?;
+    Ok(merge)
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+/// Merges the results in the database and generates the hwdb file
+///
+/// Runs in the directory containing the database.
+struct Cli {
+    /// Path of the toml config file to load
+    #[arg(short, long, value_name = "FILE")]
+    config_file: Option<String>,
+
+    /// Directory to cache the merges in between runs
+    #[arg(long, value_name = "DIR", default_value = "merge-cache")]
+    cache_dir: String,
+
+    /// Number of bootstrap resamples used to determine the confidence of
+    /// the solutions, 0 disables. Solutions with low confidence are
+    /// commented in the hwdb file.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    bootstrap: u32,
+
+    /// Merge all models again, even if their inputs didn't change
+    #[arg(short, long)]
+    force: bool,
+
+    /// Solution applied by default by the generated udev rules
+    #[arg(long, value_name = "NAME", default_value = udev::DEFAULT_SOLUTION)]
+    udev_solution: String,
+
+    /// Instead of generating the hwdb file, compare the solutions of the
+    /// models merged for several resctl-bench versions and write the
+    /// comparison to compare.md and compare.json. Exits with a non-zero
+    /// status if any parameter changed by more than the threshold.
+    #[arg(long)]
+    compare: bool,
+
+    /// Relative change of a parameter flagged by --compare
+    #[arg(long, value_name = "FRAC", default_value_t = compare::DFL_THRESHOLD)]
+    compare_threshold: f64,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Cli::parse();
+    let config = match args.config_file {
+        Some(path) => load_config(&path)?,
+        None => TomlData::default(),
+    };
+    set_version_registry(config.versions);
+    let cache = MergeCache::new(Path::new(&args.cache_dir));
+
     let merges: DashMap<String, Vec<BenchMerge>> = DashMap::new();
     // Merge result files (`resctl-bench merge`), generate pdfs and hwdb
     // files and keep the results in the `merges` hash table.
@@ -42,25 +305,30 @@ async fn main() -> Result<()> {
     //     - <version_b>
     //     - ...
     //     - <version_n>
-    for version_dir in glob("database/*").unwrap().into_iter().flatten() {
-        let version = version_dir.file_name().unwrap().to_str().unwrap();
-        if version == "2.1" {
-            println!("Ignoring 2.1 version, since it does not generate hwdb files.");
+    for version_dir in glob("database/*").unwrap().flatten() {
+        if !version_dir.is_dir() {
             continue;
         }
+        // Versions without hwdb support are merged too, they are listed
+        // in the manifest and compared but never selected.
+        let version = version_dir.file_name().unwrap().to_str().unwrap();
         let paths: Vec<PathBuf> = glob(&format!("database/{}/*", version))
             .unwrap()
-            .into_iter()
             .flatten()
             .collect();
         paths.par_iter().for_each(|model_dir: &PathBuf| {
             if model_dir.is_dir() {
                 let model_name = model_dir.file_name().unwrap().to_str().unwrap();
-                let merge = BenchMerge::merge(version.to_string(), model_name.to_string())
+                let merge = merge_model(
+                    &cache,
+                    version,
+                    model_name,
+                    &config.policy,
+                    Path::new("pdfs"),
+                    args.bootstrap,
+                    args.force,
+                )
                     .expect("Failed to merge");
-                merge
-                    .save_pdf_in(&PathBuf::from("pdfs"))
-                    .expect("Failed to save PDF");
                 merge
                     .create_hwdb_in(&PathBuf::from("hwdb-inputs"))
                     .expect("Failed to create a hwdb file");
@@ -72,6 +340,34 @@ async fn main() -> Result<()> {
         });
     }
 
+    let merges: BTreeMap<String, Vec<BenchMerge>> = merges.into_iter().collect();
+    if args.compare {
+        println!("Comparing solutions between versions...");
+        let mut comparisons = vec![];
+        for (model, alternatives) in &merges {
+            comparisons.extend(compare::compare_model(
+                model,
+                alternatives,
+                args.compare_threshold,
+            )?);
+        }
+        fs::write(
+            "compare.md",
+            compare::format_markdown(&comparisons, args.compare_threshold)?,
+        )?;
+        fs::write("compare.json", serde_json::to_string_pretty(&comparisons)?)?;
+        let mut nr_flagged = 0;
+        for cmp in &comparisons {
+            println!("{} ({}): {} big changes", cmp.model, cmp.versions.join(" "), cmp.nr_flagged());
+            nr_flagged += cmp.nr_flagged();
+        }
+        println!("{} models compared, see compare.md and compare.json", comparisons.len());
+        if nr_flagged > 0 {
+            exit(1);
+        }
+        return Ok(());
+    }
+
     println!("Generating final hwdb file...");
     let github_id = match std::env::var("GITHUB_CONTEXT") {
         Ok(context_str) => {
@@ -84,41 +380,182 @@ async fn main() -> Result<()> {
         fs::File::create("90-iocost-tune.hwdb").expect("Failed to create hwdb file");
     write_hwdb_file_header(&mut hwdb_file, github_id)?;
 
-    let models: Vec<String> = merges.iter().map(|m| m.key().clone()).collect();
-    for model in models {
-        // To override the hwdb file that is selected, you need to set
-        // the variable with the name of the model with all dashes
-        // replaced with underscores to a value that is the preferred
-        // filename. For instance:
-        //
-        // OVERRIDE_BEST_HFS256GD9TNG_62A0A_2022_09_19UTC=iocost-tune-2.2-HFS256GD9TNG-62A0A-2022-09-19UTC.hwdb
-        let override_var = format!("OVERRIDE_BEST_{}", model.replace('-', "_"));
-
-        let alternatives = merges.get(&model).unwrap();
-        let alternatives = alternatives.value();
-
-        // If override is available, select it, otherwise select the
-        // merge with the highest number of data points.
-        let best = match std::env::var(&override_var) {
-            Err(std::env::VarError::NotPresent) => {
-                let merge = alternatives.iter().max_by_key(|x| x.data_points).unwrap();
-                let best = merge.build_descriptive_filename("hwdb", None);
-                println!("{:>2} datapoints:\t{}", merge.data_points, best);
-                best
+    // Database manifest, model -> version -> entry
+    let mut manifest: BTreeMap<String, BTreeMap<String, ManifestEntry>> = BTreeMap::new();
+
+    let mut selections = vec![];
+    for (model, alternatives) in &merges {
+        let selection = select_merge(&config.policy, model, alternatives)?;
+        let hwdb_text = match selection.merge {
+            Some(merge) => Some(
+                merge.format_hwdb(|fwrev| !selection.excluded_fwrevs.contains(&fwrev))?,
+            ),
+            None => None,
+        };
+        selections.push((model, alternatives, selection, hwdb_text));
+    }
+
+    // The model in the hwdb match keys comes from the results, which may
+    // not match the database directory, e.g. if a device reported a
+    // different model name after a firmware update. Each match key is
+    // owned by the merge with the most data points, ties going to the
+    // model which sorts first, and is dropped from the other merges.
+    let mut key_owners: HashMap<HwdbMatch, (&str, usize)> = HashMap::new();
+    for (model, _, selection, hwdb_text) in selections.iter() {
+        if let (Some(merge), Some(text)) = (selection.merge, hwdb_text) {
+            for stanza in hwdb::Hwdb::parse(text).stanzas {
+                for key in stanza.matches {
+                    let owner = key_owners.entry(key).or_insert((model, merge.data_points));
+                    if merge.data_points > owner.1 {
+                        *owner = (model, merge.data_points);
+                    }
+                }
             }
-            Err(e) => panic!("Failed to interpret variable {}: {}", override_var, e),
-            Ok(best) => {
-                if !std::path::Path::exists(&PathBuf::from(&best)) {
-                    panic!("Failed to find override file: {}", best);
+        }
+    }
+
+    for (model, alternatives, mut selection, mut hwdb_text) in selections {
+        if let (Some(merge), Some(text)) = (selection.merge, hwdb_text.as_mut()) {
+            let explanation = &mut selection.explanation;
+            *text = hwdb::filter_matches(text, |key| {
+                let (owner, owner_points) = key_owners[key];
+                if owner == model.as_str() {
+                    return true;
                 }
-                println!("override:\t{}", best);
-                best
+                explanation.push(format!(
+                    "{}: dropped {}, it is also generated for {} which has {}",
+                    merge.version_str,
+                    key,
+                    owner,
+                    if owner_points > merge.data_points {
+                        "more data points"
+                    } else {
+                        "as many data points and sorts first"
+                    }
+                ));
+                false
+            });
+            if hwdb::Hwdb::parse(text).stanzas.is_empty() {
+                selection.explanation.push(format!(
+                    "{}: rejected, all its hwdb match keys belong to other models",
+                    merge.version_str
+                ));
+                selection.merge = None;
             }
-        };
-        let best_hwdb = PathBuf::from("hwdb-inputs").join(best);
-        let contents = fs::read_to_string(best_hwdb).expect("Failed to read input hwdb file");
-        writeln!(hwdb_file, "{}", contents)?;
+        }
+
+        match selection.merge {
+            Some(merge) => println!(
+                "{}: {}",
+                model,
+                merge.build_descriptive_filename("hwdb", None)
+            ),
+            None => println!("{}: no merge selected, skipping", model),
+        }
+        for line in &selection.explanation {
+            println!("    {}", line);
+        }
+        if let Some(merge) = selection.merge {
+            if !merge.low_confidence.is_empty() {
+                println!(
+                    "    low confidence solutions: {}",
+                    merge.low_confidence.join(" ")
+                );
+            }
+        }
+
+        for merge in alternatives {
+            let chosen = selection.merge.is_some_and(|m| std::ptr::eq(m, merge));
+            manifest
+                .entry(model.clone())
+                .or_default()
+                .insert(merge.version_str.clone(), ManifestEntry::new(merge, chosen));
+        }
+
+        if let (Some(_), Some(text)) = (selection.merge, hwdb_text) {
+            writeln!(hwdb_file, "{}", text)?;
+        }
     }
 
+    println!("Generating database manifest...");
+    fs::write("database/index.json", serde_json::to_string_pretty(&manifest)?)?;
+
+    // Make sure the generated file can be parsed back and is consistent
+    // before it gets published.
+    println!("Checking hwdb file...");
+    drop(hwdb_file);
+    let hwdb = hwdb::check(&fs::read_to_string("90-iocost-tune.hwdb")?)
+        .context("90-iocost-tune.hwdb failed the check")?;
+    println!("hwdb file OK, {} stanzas", hwdb.stanzas.len());
+
+    println!("Generating udev rules...");
+    for stanza in hwdb.stanzas.iter() {
+        if stanza.solution(&args.udev_solution).is_none() {
+            println!(
+                "Warning: {} has no {} solution, the udev rules won't apply it",
+                &stanza.matches[0], &args.udev_solution
+            );
+        }
+    }
+    udev::write_udev_files(Path::new("."), &args.udev_solution)?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iocost_benchmarks::common::{ModelPolicy, ResultSet};
+
+    fn merge(version: &str, data_points: usize, hwdb: bool) -> BenchMerge {
+        BenchMerge {
+            version: BenchVersion::new(version),
+            version_str: version.to_string(),
+            model_name: "model".to_string(),
+            merged: ResultSet::External {
+                version: version.to_string(),
+                path: PathBuf::new(),
+            },
+            data_points,
+            low_confidence: vec![],
+            fwmerges: vec![],
+            results: vec![],
+            hwdb: hwdb.then(String::new),
+        }
+    }
+
+    fn selected(policy: &Policy, alternatives: &[BenchMerge]) -> Option<String> {
+        select_merge(policy, "model", alternatives)
+            .unwrap()
+            .merge
+            .map(|m| m.version_str.clone())
+    }
+
+    #[test]
+    fn test_select_merge() {
+        let alternatives = [
+            merge("2.1", 100, false),
+            merge("2.2", 10, true),
+            merge("2.3", 5, true),
+        ];
+        let mut policy = Policy::default();
+        assert_eq!(selected(&policy, &alternatives).as_deref(), Some("2.2"));
+        policy.prefer_newest = true;
+        assert_eq!(selected(&policy, &alternatives).as_deref(), Some("2.3"));
+        policy.exclude_versions = vec!["2.3".to_string()];
+        assert_eq!(selected(&policy, &alternatives).as_deref(), Some("2.2"));
+
+        let pin = |version: &str| ModelPolicy {
+            pin: Some(version.to_string()),
+            ..Default::default()
+        };
+        policy.models.insert("model".to_string(), pin("2.2"));
+        assert_eq!(selected(&policy, &alternatives).as_deref(), Some("2.2"));
+        // Pinning an excluded version, a missing one or one without hwdb
+        // support is an error.
+        for version in ["2.3", "2.4", "2.1"] {
+            policy.models.insert("model".to_string(), pin(version));
+            assert!(select_merge(&policy, "model", &alternatives).is_err());
+        }
+    }
+}
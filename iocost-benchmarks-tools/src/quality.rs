@@ -0,0 +1,374 @@
+use json::JsonValue;
+use serde::{Deserialize, Serialize};
+
+/// Thresholds checked on a result before it's imported, set in the
+/// [quality.reject] and [quality.warn] sections of the config. Rules
+/// which aren't set aren't checked.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct QualityRules {
+    /// Maximum number of system requirements the benchmark missed
+    pub max_missed_sysreqs: Option<usize>,
+    /// Minimum kernel version (e.g. "5.14") the benchmark ran on
+    pub min_kernel_version: Option<String>,
+    /// Minimum number of MOF data points in the iocost-tune result
+    pub min_mof_points: Option<usize>,
+    /// The iocost-tune data must reach down to the first vrate and up
+    /// to the second one, in percents
+    pub vrate_coverage: Option<(f64, f64)>,
+    /// Maximum standard deviation of the memory size found by the
+    /// storage benchmark of each iocost-qos run, relative to the size
+    pub max_mem_size_stdev: Option<f64>,
+    /// Maximum median CPU pressure during the storage benchmark of each
+    /// iocost-qos run
+    pub max_cpu_pressure: Option<f64>,
+}
+
+/// Struct to parse the [quality] section of the config toml file.
+/// Results failing a `reject` rule aren't imported, `warn` rules only
+/// add warnings to the import.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct QualityPolicy {
+    pub reject: QualityRules,
+    pub warn: QualityRules,
+}
+
+impl Default for QualityPolicy {
+    fn default() -> Self {
+        QualityPolicy {
+            reject: QualityRules {
+                max_missed_sysreqs: Some(0),
+                min_mof_points: Some(2),
+                ..Default::default()
+            },
+            warn: QualityRules {
+                min_mof_points: Some(10),
+                vrate_coverage: Some((75.0, 100.0)),
+                max_mem_size_stdev: Some(0.1),
+                max_cpu_pressure: Some(0.1),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Reject,
+    Warn,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Severity::Reject => write!(f, "rejected"),
+            Severity::Warn => write!(f, "warning"),
+        }
+    }
+}
+
+/// A quality rule a result failed
+#[derive(Clone, Debug, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Name of the failed rule in the config
+    pub rule: &'static str,
+    /// What was found
+    pub message: String,
+    /// What the submitter can do about it
+    pub hint: String,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({}): {}", self.severity, self.rule, self.message)
+    }
+}
+
+/// Returns the first job of `kind` in `result`, the contents of a result
+/// file.
+fn find_job<'a>(result: &'a JsonValue, kind: &str) -> Option<&'a JsonValue> {
+    result.members().find(|job| job["spec"]["kind"] == kind)
+}
+
+/// Returns the leading numeric components of a kernel version, e.g.
+/// [5, 14, 0] for "5.14.0-rc6+".
+fn kernel_version_nums(version: &str) -> Vec<u64> {
+    version
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map_while(|n| n.parse().ok())
+        .collect()
+}
+
+/// Returns the vrates of the MOF data points of the iocost-tune job.
+/// Results before 2.2 store the data points in `points` instead of
+/// `data`.
+fn mof_vrates(tune: &JsonValue) -> Vec<f64> {
+    let mof = &tune["result"]["data"]["MOF"];
+    let points = if mof.has_key("data") { &mof["data"] } else { &mof["points"] };
+    points
+        .members()
+        .chain(mof["outliers"].members())
+        .filter_map(|pt| pt["x"].as_f64())
+        .collect()
+}
+
+/// Returns the maximum of `f` over the iocost-qos runs which completed.
+fn qos_runs_max(qos: &JsonValue, f: impl Fn(&JsonValue) -> Option<f64>) -> Option<f64> {
+    qos["result"]["runs"]
+        .members()
+        .filter(|run| !run.is_null())
+        .filter_map(f)
+        .reduce(f64::max)
+}
+
+impl QualityRules {
+    fn check(&self, severity: Severity, result: &JsonValue, findings: &mut Vec<Finding>) {
+        let mut add = |rule, message: String, hint: String| {
+            findings.push(Finding {
+                severity,
+                rule,
+                message,
+                hint,
+            })
+        };
+        let sysinfo = &result[0]["sysinfo"];
+
+        if let Some(max) = self.max_missed_sysreqs {
+            let missed: Vec<String> = sysinfo["sysreqs_missed"]
+                .entries()
+                .map(|(req, msgs)| {
+                    let msgs: Vec<&str> = msgs.members().filter_map(|m| m.as_str()).collect();
+                    format!("{} ({})", req, msgs.join(", "))
+                })
+                .collect();
+            if missed.len() > max {
+                add(
+                    "max_missed_sysreqs",
+                    format!("{} missed system requirements: {}", missed.len(), missed.join("; ")),
+                    "Fix the listed system requirements and run the benchmark again.".into(),
+                );
+            }
+        }
+
+        if let Some(min) = &self.min_kernel_version {
+            let kver = sysinfo["sysreqs_report"]["kernel_version"].as_str().unwrap_or_default();
+            if kernel_version_nums(kver) < kernel_version_nums(min) {
+                add(
+                    "min_kernel_version",
+                    format!("Kernel version {:?} is older than {}", kver, min),
+                    format!("Run the benchmark on kernel {} or later.", min),
+                );
+            }
+        }
+
+        let vrates = find_job(result, "iocost-tune")
+            .map(mof_vrates)
+            .unwrap_or_default();
+
+        if let Some(min) = self.min_mof_points {
+            if vrates.len() < min {
+                add(
+                    "min_mof_points",
+                    format!("Only {} MOF data points, {} required", vrates.len(), min),
+                    "Make sure the benchmark isn't interrupted and that nothing else \
+                     runs on the system while benchmarking."
+                        .into(),
+                );
+            }
+        }
+
+        if let Some((lo, hi)) = self.vrate_coverage {
+            let range = vrates
+                .iter()
+                .map(|v| (*v, *v))
+                .reduce(|(min, max), (v, _)| (min.min(v), max.max(v)));
+            let covered = match range {
+                Some((min, max)) => min <= lo && max >= hi,
+                None => false,
+            };
+            if !covered {
+                let found = match range {
+                    Some((min, max)) => format!("covers vrate {:.1}-{:.1}%", min, max),
+                    None => "is empty".into(),
+                };
+                add(
+                    "vrate_coverage",
+                    format!("iocost-tune data {}, {:.1}-{:.1}% required", found, lo, hi),
+                    "Make sure the iocost-qos runs span the default vrate range and \
+                     aren't skipped or failing."
+                        .into(),
+                );
+            }
+        }
+
+        let qos = match find_job(result, "iocost-qos") {
+            Some(qos) => qos,
+            None => return,
+        };
+        let quiet_hint = "The system was likely busy while benchmarking, stop other \
+                          workloads and run the benchmark again.";
+
+        if let Some(max) = self.max_mem_size_stdev {
+            let stdev = qos_runs_max(qos, |run| {
+                Some(run["stor"]["mem_size_stdev"].as_f64()? / run["stor"]["mem_size"].as_f64()?)
+            });
+            if let Some(stdev) = stdev.filter(|stdev| *stdev > max) {
+                add(
+                    "max_mem_size_stdev",
+                    format!(
+                        "Memory size measurements vary by up to {:.1}%, at most {:.1}% allowed",
+                        stdev * 100.0,
+                        max * 100.0
+                    ),
+                    quiet_hint.into(),
+                );
+            }
+        }
+
+        if let Some(max) = self.max_cpu_pressure {
+            let pressure = qos_runs_max(qos, |run| run["stor"]["all_rstat"]["psi_cpu"]["50"].as_f64());
+            if let Some(pressure) = pressure.filter(|pressure| *pressure > max) {
+                add(
+                    "max_cpu_pressure",
+                    format!(
+                        "Median CPU pressure reached {:.1}%, at most {:.1}% allowed",
+                        pressure * 100.0,
+                        max * 100.0
+                    ),
+                    quiet_hint.into(),
+                );
+            }
+        }
+    }
+}
+
+impl QualityPolicy {
+    /// Checks `result`, the contents of a result file, against the
+    /// reject and warn rules and returns the rules it failed, rejections
+    /// first.
+    pub fn check(&self, result: &JsonValue) -> Vec<Finding> {
+        let mut findings = vec![];
+        self.reject.check(Severity::Reject, result, &mut findings);
+        let mut warnings = vec![];
+        self.warn.check(Severity::Warn, result, &mut warnings);
+        // Don't warn about what's already rejected.
+        warnings.retain(|w| !findings.iter().any(|f| f.rule == w.rule));
+        findings.extend(warnings);
+        findings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QualityPolicy, QualityRules, Severity};
+    use json::{array, object, JsonValue};
+
+    /// A result with the given missed sysreqs, kernel version, MOF data
+    /// point vrates and per-run storage stats (mem_size_stdev fraction,
+    /// median CPU pressure).
+    fn result(missed: usize, kver: &str, vrates: &[f64], runs: &[(f64, f64)]) -> JsonValue {
+        let mut sysreqs_missed = JsonValue::new_object();
+        for idx in 0..missed {
+            sysreqs_missed[format!("Req{}", idx)] = array!["missing"];
+        }
+        let points: Vec<JsonValue> = vrates.iter().map(|x| object! {x: *x, y: 1.0}).collect();
+        let runs: Vec<JsonValue> = runs
+            .iter()
+            .map(|(stdev, psi)| {
+                object! {
+                    stor: {
+                        mem_size: 1000.0,
+                        mem_size_stdev: stdev * 1000.0,
+                        all_rstat: { psi_cpu: { "50": *psi } },
+                    }
+                }
+            })
+            .collect();
+        let mut qos = object! { spec: { kind: "iocost-qos" } };
+        qos["sysinfo"]["sysreqs_missed"] = sysreqs_missed;
+        qos["sysinfo"]["sysreqs_report"]["kernel_version"] = kver.into();
+        qos["result"]["runs"] = runs.into();
+        let mut tune = object! { spec: { kind: "iocost-tune" } };
+        tune["result"]["data"]["MOF"]["data"] = points.into();
+        array![qos, tune]
+    }
+
+    fn good() -> JsonValue {
+        let vrates: Vec<f64> = (1..=20).map(|v| v as f64 * 5.0).collect();
+        result(0, "6.1.0-rc1+", &vrates, &[(0.01, 0.01), (0.02, 0.02)])
+    }
+
+    fn rules_of(policy: &QualityPolicy, result: &JsonValue) -> Vec<(Severity, &'static str)> {
+        policy
+            .check(result)
+            .iter()
+            .map(|f| (f.severity, f.rule))
+            .collect()
+    }
+
+    #[test]
+    fn test_quality_check() {
+        let rules = QualityRules {
+            max_missed_sysreqs: Some(0),
+            min_kernel_version: Some("5.14".into()),
+            min_mof_points: Some(10),
+            vrate_coverage: Some((10.0, 100.0)),
+            max_mem_size_stdev: Some(0.1),
+            max_cpu_pressure: Some(0.1),
+        };
+        let vrates: Vec<f64> = (1..=20).map(|v| v as f64 * 5.0).collect();
+        let bad: &[(&str, JsonValue)] = &[
+            ("max_missed_sysreqs", result(1, "6.1.0", &vrates, &[])),
+            ("min_kernel_version", result(0, "5.9.0", &vrates, &[])),
+            ("min_mof_points", result(0, "6.1.0", &[5.0, 50.0, 100.0], &[])),
+            ("vrate_coverage", result(0, "6.1.0", &vrates[2..], &[])),
+            ("max_mem_size_stdev", result(0, "6.1.0", &vrates, &[(0.01, 0.0), (0.2, 0.0)])),
+            ("max_cpu_pressure", result(0, "6.1.0", &vrates, &[(0.0, 0.3)])),
+        ];
+
+        let reject = QualityPolicy {
+            reject: rules.clone(),
+            warn: Default::default(),
+        };
+        let warn = QualityPolicy {
+            reject: Default::default(),
+            warn: rules.clone(),
+        };
+        assert!(reject.check(&good()).is_empty());
+        assert!(warn.check(&good()).is_empty());
+        for (rule, result) in bad.iter() {
+            assert_eq!(rules_of(&reject, result), vec![(Severity::Reject, *rule)]);
+            assert_eq!(rules_of(&warn, result), vec![(Severity::Warn, *rule)]);
+        }
+    }
+
+    #[test]
+    fn test_quality_check_dedup() {
+        let policy = QualityPolicy::default();
+        assert!(policy.check(&good()).is_empty());
+
+        // min_mof_points is both a reject and a warn rule, only the
+        // rejection is reported.
+        let res = result(0, "6.1.0", &[50.0], &[]);
+        assert_eq!(
+            rules_of(&policy, &res),
+            vec![
+                (Severity::Reject, "min_mof_points"),
+                (Severity::Warn, "vrate_coverage")
+            ]
+        );
+
+        // Passing the reject threshold still warns.
+        let vrates: Vec<f64> = (1..=5).map(|v| v as f64 * 20.0).collect();
+        let res = result(0, "6.1.0", &vrates, &[]);
+        assert_eq!(
+            rules_of(&policy, &res),
+            vec![(Severity::Warn, "min_mof_points")]
+        );
+    }
+}
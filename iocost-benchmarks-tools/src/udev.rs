@@ -0,0 +1,153 @@
+use anyhow::{bail, Result};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+pub const RULES_FILE: &str = "91-iocost-tune.rules";
+pub const APPLIER_FILE: &str = "iocost-tune-apply";
+
+/// Solution applied when merge-results isn't told otherwise
+pub const DEFAULT_SOLUTION: &str = "isolated-bandwidth";
+
+/// Returns the udev rules which look up the hwdb entry of block devices
+/// and run the applier on the ones which have a solution. The solution is
+/// `solution` unless IOCOST_SOLUTION is already set by an earlier rule.
+fn rules(solution: &str) -> String {
+    format!(
+        r#"# Applies the iocost parameters from 90-iocost-tune.hwdb, see {applier}.
+# Set IOCOST_SOLUTION in an earlier rule to apply another solution.
+
+ACTION!="add|change", GOTO="iocost_tune_end"
+SUBSYSTEM!="block", GOTO="iocost_tune_end"
+ENV{{DEVTYPE}}!="disk", GOTO="iocost_tune_end"
+
+# NVMe devices report the firmware revision in firmware_rev, others in rev.
+ATTR{{device/firmware_rev}}=="?*", IMPORT{{builtin}}="hwdb 'block:$devpath:name:$attr{{device/model}}:fwrev:$attr{{device/firmware_rev}}:'", GOTO="iocost_tune_apply"
+ATTR{{device/rev}}=="?*", IMPORT{{builtin}}="hwdb 'block:$devpath:name:$attr{{device/model}}:fwrev:$attr{{device/rev}}:'"
+
+LABEL="iocost_tune_apply"
+ENV{{IOCOST_SOLUTIONS}}!="?*", GOTO="iocost_tune_end"
+ENV{{IOCOST_SOLUTION}}!="?*", ENV{{IOCOST_SOLUTION}}="{solution}"
+RUN+="{applier} $major:$minor"
+
+LABEL="iocost_tune_end"
+"#,
+        applier = APPLIER_FILE,
+        solution = solution
+    )
+}
+
+/// Returns the applier run by the udev rules. It writes the model and QoS
+/// parameters of the IOCOST_SOLUTION solution the same way
+/// rd_util::IoCostSysSave::write_to_sys() does with user control enabled.
+fn applier() -> String {
+    format!(
+        r#"#!/bin/sh
+# Usage: {applier} MAJ:MIN
+#
+# Run by {rules} with the properties of the device from
+# 90-iocost-tune.hwdb in the environment. Writes the IOCOST_MODEL_* and
+# IOCOST_QOS_* properties of the IOCOST_SOLUTION solution to
+# /sys/fs/cgroup/io.cost.model and io.cost.qos.
+
+set -e
+
+DEVNR="$1"
+if [ -z "$DEVNR" ] || [ -z "$IOCOST_SOLUTION" ]; then
+    echo "{applier}: usage: IOCOST_SOLUTION=NAME {applier} MAJ:MIN" >&2
+    exit 1
+fi
+
+# IOCOST_SOLUTION may come from anywhere, only accept what hwdb property
+# names can contain.
+NAME=$(echo "$IOCOST_SOLUTION" | tr 'a-z-' 'A-Z_')
+case "$NAME" in
+    *[!A-Z0-9_]*)
+        echo "{applier}: invalid solution name $IOCOST_SOLUTION" >&2
+        exit 1;;
+esac
+
+if [ ! -w /sys/fs/cgroup/io.cost.model ] || [ ! -w /sys/fs/cgroup/io.cost.qos ]; then
+    echo "{applier}: io.cost is not available, is cgroup2 mounted on /sys/fs/cgroup?" >&2
+    exit 1
+fi
+
+MODEL=$(env | sed -n "s/^IOCOST_MODEL_${{NAME}}=//p")
+QOS=$(env | sed -n "s/^IOCOST_QOS_${{NAME}}=//p")
+if [ -z "$MODEL" ] || [ -z "$QOS" ]; then
+    echo "{applier}: no $IOCOST_SOLUTION solution for $DEVNR" >&2
+    exit 1
+fi
+
+echo "$DEVNR ctrl=user $MODEL" > /sys/fs/cgroup/io.cost.model
+echo "$DEVNR enable=1 ctrl=user $QOS" > /sys/fs/cgroup/io.cost.qos
+"#,
+        applier = APPLIER_FILE,
+        rules = RULES_FILE
+    )
+}
+
+/// Writes the udev rules and the applier in `dir` to apply the hwdb
+/// entries with the `solution` solution by default.
+pub fn write_udev_files(dir: &Path, solution: &str) -> Result<()> {
+    if solution.is_empty()
+        || !solution
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        bail!("Invalid solution name {:?}", solution);
+    }
+
+    fs::write(dir.join(RULES_FILE), rules(solution))?;
+    let applier_path = dir.join(APPLIER_FILE);
+    fs::write(&applier_path, applier())?;
+    fs::set_permissions(&applier_path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_write_udev_files() {
+        let dir = tempfile::tempdir().unwrap();
+        write_udev_files(dir.path(), "rlat-99-q1").unwrap();
+
+        let rules = fs::read_to_string(dir.path().join(RULES_FILE)).unwrap();
+        assert!(rules.contains(r#"ENV{IOCOST_SOLUTION}!="?*", ENV{IOCOST_SOLUTION}="rlat-99-q1""#));
+        assert!(rules.contains(&format!(r#"RUN+="{} $major:$minor""#, APPLIER_FILE)));
+        assert!(rules.contains(":fwrev:$attr{device/firmware_rev}:'"));
+
+        let meta = fs::metadata(dir.path().join(APPLIER_FILE)).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o755);
+
+        for name in ["", "Isolation", "rlat_99", "a b", "$(reboot)"] {
+            assert!(write_udev_files(dir.path(), name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_applier_rejects_bad_names() {
+        let dir = tempfile::tempdir().unwrap();
+        write_udev_files(dir.path(), DEFAULT_SOLUTION).unwrap();
+        let marker = dir.path().join("marker");
+
+        for name in [
+            format!("x;touch {}", marker.display()),
+            format!("$(touch {})", marker.display()),
+            "a b".to_string(),
+        ] {
+            let out = Command::new("sh")
+                .arg(dir.path().join(APPLIER_FILE))
+                .arg("8:0")
+                .env("IOCOST_SOLUTION", &name)
+                .output()
+                .unwrap();
+            assert!(!out.status.success());
+            assert!(String::from_utf8_lossy(&out.stderr).contains("invalid solution name"));
+            assert!(!marker.exists(), "{:?}", name);
+        }
+    }
+}
@@ -287,7 +287,7 @@ impl JsonArgs for Args {
     fn match_cmdline() -> clap::ArgMatches<'static> {
         clap::App::new("rd-agent")
             .version((*super::FULL_VERSION).as_str())
-            .author(clap::crate_authors!("\n"))
+            .author(env!("CARGO_PKG_AUTHORS"))
             .about(*HELP_BODY.lock().unwrap())
             .args_from_usage(&ARGS_STR)
             .subcommand(
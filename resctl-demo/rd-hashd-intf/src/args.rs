@@ -196,7 +196,7 @@ impl JsonArgs for Args {
     fn match_cmdline() -> ArgMatches<'static> {
         App::new("rd-hashd")
             .version((*super::FULL_VERSION).as_str())
-            .author(clap::crate_authors!("\n"))
+            .author(env!("CARGO_PKG_AUTHORS"))
             .about(*HELP_BODY.lock().unwrap())
             .args_from_usage(&ARGS_STR)
             .setting(AppSettings::UnifiedHelpMessage)
@@ -76,7 +76,8 @@ impl IoCostQoSParams {
     }
 }
 
-/// Save /sys/fs/cgroup/io.cost.model,qos and restore them on drop.
+/// Save /sys/fs/cgroup/io.cost.model,qos and restore them on drop if
+/// `restore` is set, which read_from_sys() does.
 #[derive(Default)]
 pub struct IoCostSysSave {
     pub devnr: (u32, u32),
@@ -85,6 +86,7 @@ pub struct IoCostSysSave {
     pub qos_ctrl_user: bool,
     pub model: IoCostModelParams,
     pub qos: IoCostQoSParams,
+    pub restore: bool,
 }
 
 impl IoCostSysSave {
@@ -97,6 +99,7 @@ impl IoCostSysSave {
 
         let mut params = IoCostSysSave::default();
         params.devnr = devnr;
+        params.restore = true;
 
         let model = match model.get(&devnr_str) {
             Some(v) => v,
@@ -128,7 +131,9 @@ impl IoCostSysSave {
         Ok(params)
     }
 
-    pub fn write_to_sys(&self) -> Result<()> {
+    /// Returns the lines write_to_sys() writes to io.cost.model and
+    /// io.cost.qos.
+    pub fn format_sys(&self) -> (String, String) {
         let devnr_str = format!("{}:{}", self.devnr.0, self.devnr.1);
         let model = match self.model_ctrl_user {
             false => format!("{} ctrl=auto", &devnr_str),
@@ -159,6 +164,11 @@ impl IoCostSysSave {
             .unwrap(),
         }
 
+        (model, qos)
+    }
+
+    pub fn write_to_sys(&self) -> Result<()> {
+        let (model, qos) = self.format_sys();
         fs::OpenOptions::new()
             .write(true)
             .open("/sys/fs/cgroup/io.cost.model")?
@@ -173,6 +183,9 @@ impl IoCostSysSave {
 
 impl Drop for IoCostSysSave {
     fn drop(&mut self) {
+        if !self.restore {
+            return;
+        }
         if let Err(e) = self.write_to_sys() {
             error!("Failed to restore io.cost.model,qos ({})", &e);
         }
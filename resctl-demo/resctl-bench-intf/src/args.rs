@@ -49,6 +49,10 @@ lazy_static::lazy_static! {
             dfl_systemd_timeout = format_duration(dfl_args.systemd_timeout),
         )
     };
+    static ref HWDB_SOLUTION_HELP: String = format!(
+        "Solution to apply (dfl: {})",
+        Args::DFL_HWDB_SOLUTION
+    );
     static ref HELP_BODY: Mutex<&'static str> = Mutex::new("");
     static ref AFTER_HELP: Mutex<&'static str> = Mutex::new("");
     static ref DOC_AFTER_HELP: Mutex<&'static str> = Mutex::new("");
@@ -119,6 +123,7 @@ pub enum Mode {
     Upload,
     Pack,
     Merge,
+    ApplyHwdb,
     Deps,
     Doc,
 }
@@ -176,6 +181,8 @@ pub struct Args {
     #[serde(skip)]
     pub merge_multiple: bool,
     #[serde(skip)]
+    pub merge_bootstrap: u32,
+    #[serde(skip)]
     pub upload_email: Option<String>,
     #[serde(skip)]
     pub upload_github: Option<String>,
@@ -183,6 +190,16 @@ pub struct Args {
     pub upload_url: Option<String>,
     #[serde(skip)]
     pub doc_subjects: Vec<String>,
+    #[serde(skip)]
+    pub hwdb_file: String,
+    #[serde(skip)]
+    pub hwdb_solution: String,
+    #[serde(skip)]
+    pub hwdb_apply: bool,
+    #[serde(skip)]
+    pub hwdb_dry_run: bool,
+    #[serde(skip)]
+    pub hwdb_restore: bool,
 }
 
 impl Default for Args {
@@ -219,10 +236,16 @@ impl Default for Args {
             merge_ignore_versions: false,
             merge_ignore_sysreqs: false,
             merge_multiple: false,
+            merge_bootstrap: 0,
             upload_email: None,
             upload_github: None,
             upload_url: None,
             doc_subjects: vec![],
+            hwdb_file: "".into(),
+            hwdb_solution: Self::DFL_HWDB_SOLUTION.into(),
+            hwdb_apply: false,
+            hwdb_dry_run: false,
+            hwdb_restore: false,
         }
     }
 }
@@ -230,6 +253,7 @@ impl Default for Args {
 impl Args {
     pub const RB_BENCH_FILENAME: &'static str = "rb-bench.json";
     pub const DFL_MEM_PROFILE: u32 = 16;
+    pub const DFL_HWDB_SOLUTION: &'static str = "isolated-bandwidth";
 
     pub fn set_help_body(help: &'static str) {
         *HELP_BODY.lock().unwrap() = help;
@@ -398,7 +422,7 @@ impl JsonArgs for Args {
 
         let mut app = clap::App::new("resctl-bench")
             .version((*super::FULL_VERSION).as_str())
-            .author(clap::crate_authors!("\n"))
+            .author(env!("CARGO_PKG_AUTHORS"))
             .about("Facebook Resource Control Benchmarks")
             .setting(clap::AppSettings::UnifiedHelpMessage)
             .setting(clap::AppSettings::DeriveDisplayOrder)
@@ -481,6 +505,46 @@ impl JsonArgs for Args {
                             .long("multiple")
                             .help("Allow more than one result per kind (and optionally id)")
                     )
+                    .arg(
+                        clap::Arg::with_name("bootstrap")
+                            .long("bootstrap")
+                            .takes_value(true)
+                            .help("Number of bootstrap resamples for the iocost-tune confidence (default: 0)")
+                    )
+            )
+            .subcommand(
+                clap::SubCommand::with_name("apply-hwdb")
+                    .about("Looks up the scratch device in a hwdb file generated from the community database and applies its solution")
+                    .arg(
+                        clap::Arg::with_name("HWDBFILE")
+                            .required(true)
+                            .help("hwdb file, e.g. 90-iocost-tune.hwdb")
+                    )
+                    .arg(
+                        clap::Arg::with_name("solution")
+                            .long("solution")
+                            .short("s")
+                            .takes_value(true)
+                            .number_of_values(1)
+                            .help(&HWDB_SOLUTION_HELP)
+                    )
+                    .arg(
+                        clap::Arg::with_name("apply")
+                            .long("apply")
+                            .help("Write the solution to io.cost.model and io.cost.qos")
+                    )
+                    .arg(
+                        clap::Arg::with_name("dry-run")
+                            .long("dry-run")
+                            .requires("apply")
+                            .help("Show what --apply would write without writing it")
+                    )
+                    .arg(
+                        clap::Arg::with_name("restore-on-exit")
+                            .long("restore-on-exit")
+                            .requires("apply")
+                            .help("Wait for SIGINT/TERM after applying and restore the previous configuration")
+                    )
             )
             .subcommand(
                 clap::App::new("upload")
@@ -692,6 +756,9 @@ impl JsonArgs for Args {
                 self.merge_ignore_versions = subm.is_present("ignore-versions");
                 self.merge_ignore_sysreqs = subm.is_present("ignore-sysreqs");
                 self.merge_multiple = subm.is_present("multiple");
+                if let Some(v) = subm.value_of("bootstrap") {
+                    self.merge_bootstrap = v.parse::<u32>().expect("Invalid bootstrap");
+                }
                 self.merge_srcs = subm
                     .values_of("SOURCEFILE")
                     .unwrap()
@@ -699,6 +766,18 @@ impl JsonArgs for Args {
                     .collect();
                 false
             }
+            ("apply-hwdb", Some(subm)) => {
+                self.mode = Mode::ApplyHwdb;
+                self.hwdb_file = subm.value_of("HWDBFILE").unwrap().into();
+                self.hwdb_solution = subm
+                    .value_of("solution")
+                    .unwrap_or(Self::DFL_HWDB_SOLUTION)
+                    .into();
+                self.hwdb_apply = subm.is_present("apply");
+                self.hwdb_dry_run = subm.is_present("dry-run");
+                self.hwdb_restore = subm.is_present("restore-on-exit");
+                false
+            }
             ("deps", Some(_subm)) => {
                 self.mode = Mode::Deps;
                 false
@@ -715,7 +794,11 @@ impl JsonArgs for Args {
             _ => false,
         };
 
-        if self.mode != Mode::Doc && self.mode != Mode::Deps && self.result.len() == 0 {
+        if self.mode != Mode::Doc
+            && self.mode != Mode::Deps
+            && self.mode != Mode::ApplyHwdb
+            && self.result.len() == 0
+        {
             error!("{:?} requires --result", &self.mode);
             exit(1);
         }
@@ -499,15 +499,14 @@ impl<'a> Base<'a> {
                 Ok((false, "base: Testing shadow inode protection...".into()))
             })?;
 
-        let protected = rctx.access_agent_files(|af| {
-            match af.report.data.sysloads.get(INODESTEAL_TEST.into()) {
+        let protected =
+            rctx.access_agent_files(|af| match af.report.data.sysloads.get(INODESTEAL_TEST) {
                 Some(rep) => Ok(rep.svc.state == SvcStateReport::Exited),
                 None => Err(anyhow!(
                     "base: Can't find {} service after testing",
                     INODESTEAL_TEST
                 )),
-            }
-        });
+            });
         drop(rctx);
 
         self.shadow_inode_protected = protected?;
@@ -19,7 +19,7 @@ use super::progress::BenchProgress;
 use super::run::{RunCtx, WorkloadMon};
 use super::study::*;
 use rd_agent_intf::{AgentFiles, EnforceConfig, Slice, SysReq, ROOT_SLICE};
-use resctl_bench_intf::{format_job_props, JobProps, JobSpec};
+use resctl_bench_intf::{format_job_props, Args, JobProps, JobSpec};
 
 use rd_util::*;
 
@@ -220,7 +220,7 @@ pub trait Bench: Send + Sync {
     fn merge_classifier(&self, _data: &JobData) -> Option<String> {
         None
     }
-    fn merge(&self, _srcs: &mut Vec<MergeSrc>) -> Result<JobData> {
+    fn merge(&self, _args: &Args, _srcs: &mut Vec<MergeSrc>) -> Result<JobData> {
         bail!("not implemented");
     }
     fn doc<'a>(&self, out: &mut Box<dyn Write + 'a>) -> Result<()> {
@@ -19,6 +19,10 @@ const DFL_RETRIES: u32 = 1;
 // Don't go below 1% of the specified model when applying vrate-intvs.
 const VRATE_INTVS_MIN: f64 = 1.0;
 
+// Adaptive sampling doesn't probe vrates closer than this to the already
+// probed ones.
+const ADAPTIVE_MIN_DIST: f64 = 1.0;
+
 #[derive(Default)]
 pub struct IoCostQoSJob {
     stor_base_loops: u32,
@@ -26,6 +30,11 @@ pub struct IoCostQoSJob {
     isol_pct: String,
     isol_thr: f64,
     dither_dist: Option<f64>,
+    adaptive: bool,
+    adaptive_budget: Option<f64>,
+    // Without an explicit budget, the number of runs the coarse sweep
+    // saved compared to the full vrate-intvs sweep.
+    adaptive_runs: usize,
     ign_min_perf: bool,
     retries: u32,
     allow_fail: bool,
@@ -136,6 +145,8 @@ impl IoCostQoSJob {
         }];
         let mut dither = false;
         let mut dither_dist = None;
+        let mut adaptive = false;
+        let mut adaptive_budget = None;
         let mut ign_min_perf = false;
 
         for (k, v) in spec.props[0].iter() {
@@ -149,6 +160,12 @@ impl IoCostQoSJob {
                         dither_dist = Some(v.parse::<f64>()?);
                     }
                 }
+                "adaptive" => {
+                    adaptive = true;
+                    if v.len() > 0 {
+                        adaptive_budget = Some(parse_duration(v)?);
+                    }
+                }
                 "storage-base-loops" => stor_base_loops = v.parse::<u32>()?,
                 "storage-loops" => stor_loops = v.parse::<u32>()?,
                 "isol-pct" => isol_pct = v.to_owned(),
@@ -187,20 +204,19 @@ impl IoCostQoSJob {
             vrate_intvs = DFL_VRATE_INTVS;
         }
 
+        let mut adaptive_runs = 0;
         if vrate_intvs > 0 {
-            // min of 0 is special case and means that we start at one
-            // click, so if min is 0, max is 10 and intvs is 5, the sequence
-            // is (10, 7.5, 5, 2.5). If min > 0, the range is inclusive -
-            // min 5, max 10, intvs 5 => (10, 9, 8, 7, 6, 5).
-            let click;
-            let mut dither_shift = 0.0;
-            if vrate_min == 0.0 {
-                click = vrate_max / vrate_intvs as f64;
-                vrate_min = click;
-                dither_shift = -click / 2.0;
-            } else {
-                click = (vrate_max - vrate_min) / (vrate_intvs - 1) as f64;
-            };
+            // With adaptive sampling, the sweep is coarsened and the runs
+            // saved are used to probe around the hinges instead.
+            if adaptive {
+                let intvs = Self::adaptive_sweep_intvs(vrate_intvs);
+                adaptive_runs = Self::sweep_vrates(vrate_min, vrate_max, vrate_intvs)
+                    .len()
+                    .saturating_sub(Self::sweep_vrates(vrate_min, vrate_max, intvs).len());
+                vrate_intvs = intvs;
+            }
+
+            let (click, dither_shift) = Self::sweep_click(&mut vrate_min, vrate_max, vrate_intvs);
 
             if dither {
                 if dither_dist.is_none() {
@@ -224,10 +240,7 @@ impl IoCostQoSJob {
                 vrate_max += dither_dist.as_ref().unwrap();
             }
 
-            vrate_min = vrate_min.max(VRATE_INTVS_MIN);
-
-            let mut vrate = vrate_max;
-            while vrate > vrate_min - 0.001 {
+            for vrate in Self::click_vrates(vrate_min, vrate_max, click) {
                 let mut ovr = IoCostQoSOvr {
                     min: Some(vrate),
                     max: Some(vrate),
@@ -235,7 +248,6 @@ impl IoCostQoSJob {
                 };
                 ovr.sanitize();
                 runs.push(ovr);
-                vrate -= click;
             }
         }
 
@@ -245,6 +257,9 @@ impl IoCostQoSJob {
             isol_pct,
             isol_thr,
             dither_dist,
+            adaptive,
+            adaptive_budget,
+            adaptive_runs,
             ign_min_perf,
             retries,
             allow_fail,
@@ -389,6 +404,279 @@ impl IoCostQoSJob {
         })
     }
 
+    fn run_ovr(
+        &self,
+        rctx: &mut RunCtx,
+        i: usize,
+        qos_cfg: &IoCostQoSCfg,
+        prev_rec: &mut IoCostQoSRecord,
+    ) -> Result<IoCostQoSRecordRun> {
+        let mut sjob = self.stor_job.clone();
+        sjob.loops = match i {
+            0 => self.stor_base_loops,
+            _ => self.stor_loops,
+        };
+        let mut pjob = self.prot_job.clone();
+
+        let recr = Self::run_one(rctx, &mut sjob, &mut pjob, qos_cfg, self.retries)?;
+
+        // Sanity check QoS params.
+        if recr.qos.is_some() {
+            let target_qos = qos_cfg.calc();
+            if recr.qos != target_qos {
+                bail!(
+                    "iocost-qos: result qos ({}) != target qos ({})",
+                    &recr.qos.as_ref().unwrap(),
+                    target_qos.as_ref().unwrap(),
+                );
+            }
+        }
+        prev_rec.inc_runs.push(recr.clone());
+        rctx.update_incremental_record(serde_json::to_value(&*prev_rec).unwrap());
+        Ok(recr)
+    }
+
+    fn fixed_vrate(ovr: &IoCostQoSOvr) -> Option<f64> {
+        match ovr {
+            IoCostQoSOvr {
+                off: false,
+                rpct: None,
+                rlat: None,
+                wpct: None,
+                wlat: None,
+                min: Some(min),
+                max: Some(max),
+                ..
+            } if min == max => Some(*min),
+            _ => None,
+        }
+    }
+
+    /// The interval between the vrates of a sweep of @intvs intervals up
+    /// to @vrate_max and the dither shift. min of 0 is special case and
+    /// means that we start at one click, so if min is 0, max is 10 and
+    /// intvs is 5, the sequence is (10, 7.5, 5, 2.5) and @vrate_min is
+    /// updated to 2.5. If min > 0, the range is inclusive - min 5, max 10,
+    /// intvs 5 => (10, 9, 8, 7, 6, 5).
+    fn sweep_click(vrate_min: &mut f64, vrate_max: f64, intvs: u32) -> (f64, f64) {
+        if *vrate_min == 0.0 {
+            let click = vrate_max / intvs as f64;
+            *vrate_min = click;
+            (click, -click / 2.0)
+        } else {
+            ((vrate_max - *vrate_min) / (intvs - 1) as f64, 0.0)
+        }
+    }
+
+    /// The vrates from @vrate_max down to @vrate_min every @click.
+    fn click_vrates(vrate_min: f64, vrate_max: f64, click: f64) -> Vec<f64> {
+        let vrate_min = vrate_min.max(VRATE_INTVS_MIN);
+        let mut vrates = vec![];
+        let mut vrate = vrate_max;
+        while vrate > vrate_min - 0.001 {
+            vrates.push(vrate);
+            vrate -= click;
+        }
+        vrates
+    }
+
+    /// The vrates of an undithered sweep of @intvs intervals.
+    fn sweep_vrates(mut vrate_min: f64, vrate_max: f64, intvs: u32) -> Vec<f64> {
+        let (click, _) = Self::sweep_click(&mut vrate_min, vrate_max, intvs);
+        Self::click_vrates(vrate_min, vrate_max, click)
+    }
+
+    /// The number of intervals of the coarse sweep which adaptive sampling
+    /// starts with, half of @intvs.
+    fn adaptive_sweep_intvs(intvs: u32) -> u32 {
+        ((intvs + 1) / 2).max(2).min(intvs)
+    }
+
+    /// Pick the next vrate to probe for adaptive sampling. For each hinge,
+    /// the hinge itself and the midpoints between it and the neighboring
+    /// @probed vrates are considered and the candidate farthest away from
+    /// all @probed vrates wins. None if none is at least ADAPTIVE_MIN_DIST
+    /// away.
+    fn pick_adaptive_vrate(hinges: &[f64], probed: &[f64]) -> Option<f64> {
+        let dist = |vrate: f64| {
+            probed
+                .iter()
+                .map(|p| (p - vrate).abs())
+                .fold(std::f64::INFINITY, f64::min)
+        };
+
+        let mut best: Option<(f64, f64)> = None;
+        for hinge in hinges.iter().cloned() {
+            let below = probed
+                .iter()
+                .cloned()
+                .filter(|p| *p < hinge)
+                .fold(None, |acc, p| Some(acc.map_or(p, |a: f64| a.max(p))));
+            let above = probed
+                .iter()
+                .cloned()
+                .filter(|p| *p > hinge)
+                .fold(None, |acc, p| Some(acc.map_or(p, |a: f64| a.min(p))));
+            let cands = [
+                Some(hinge),
+                below.map(|b| (b + hinge) / 2.0),
+                above.map(|a| (a + hinge) / 2.0),
+            ];
+            for cand in cands.iter().filter_map(|c| *c) {
+                let d = dist(cand);
+                if d >= ADAPTIVE_MIN_DIST && best.map_or(true, |(_, bd)| d > bd) {
+                    best = Some((cand, d));
+                }
+            }
+        }
+        best.map(|(vrate, _)| vrate)
+    }
+
+    /// After the coarse sweep in self.runs is complete, keep fitting the
+    /// results the way iocost-tune does and probe around the hinges of the
+    /// fitted lines until the budget runs out or there's nothing left
+    /// worth probing. The budget is self.adaptive_budget if set, otherwise
+    /// self.adaptive_runs runs. The extra runs are appended to self.runs
+    /// and @runs.
+    fn run_adaptive(
+        &mut self,
+        rctx: &mut RunCtx,
+        bench_knobs: &BenchKnobs,
+        prev_matches: bool,
+        prev_rec: &mut IoCostQoSRecord,
+        runs: &mut Vec<Option<IoCostQoSRecordRun>>,
+    ) -> Result<()> {
+        let duration = |recr: &IoCostQoSRecordRun| (recr.period.1 - recr.period.0) as f64;
+        let nr_sweep_runs = self.runs.len();
+
+        // Pick up the adaptive runs from the previous result so that an
+        // interrupted benchmark resumes where it left off.
+        if prev_matches {
+            for recr in prev_rec
+                .runs
+                .iter()
+                .filter_map(|x| x.as_ref())
+                .chain(prev_rec.inc_runs.iter())
+            {
+                if Self::fixed_vrate(&recr.ovr).is_some() && !self.runs.contains(&recr.ovr) {
+                    self.runs.push(recr.ovr.clone());
+                    runs.push(Some(recr.clone()));
+                }
+            }
+        }
+
+        let abs_min_vrate = iocost_min_vrate(&bench_knobs.iocost.model);
+        let mut results: Vec<Option<IoCostQoSResultRun>> = vec![];
+        loop {
+            if prog_exiting() {
+                bail!("Program exiting");
+            }
+
+            // Estimate how long another run would take from the QoS runs
+            // so far.
+            let spent: f64 = runs.iter().filter_map(|x| x.as_ref()).map(duration).sum();
+            let qos_durs: Vec<f64> = runs
+                .iter()
+                .filter_map(|x| x.as_ref())
+                .filter(|recr| recr.qos.is_some())
+                .map(duration)
+                .collect();
+            if qos_durs.is_empty() {
+                break;
+            }
+            let per_run = statistical::mean(&qos_durs);
+            match self.adaptive_budget {
+                Some(budget) if spent + per_run > budget => {
+                    info!(
+                        "iocost-qos: Adaptive sampling budget exhausted ({} spent, {} per run, {} budget)",
+                        format_duration(spent),
+                        format_duration(per_run),
+                        format_duration(budget)
+                    );
+                    break;
+                }
+                None if self.runs.len() - nr_sweep_runs >= self.adaptive_runs => {
+                    info!(
+                        "iocost-qos: Adaptive sampling done, {} runs saved by the coarse sweep used",
+                        self.adaptive_runs
+                    );
+                    break;
+                }
+                _ => {}
+            }
+
+            while results.len() < runs.len() {
+                results.push(match runs[results.len()].as_ref() {
+                    Some(recr) => Some(self.study_one(rctx, recr)?),
+                    None => None,
+                });
+            }
+            let hinges = super::iocost_tune::qos_hinge_vrates(
+                &IoCostQoSRecord {
+                    runs: runs.clone(),
+                    ..Default::default()
+                },
+                &IoCostQoSResult {
+                    runs: results.clone(),
+                },
+                &self.isol_pct,
+                self.isol_thr,
+            )?;
+
+            // Failed runs count as probed so that they aren't retried.
+            let probed: Vec<f64> = self.runs.iter().filter_map(Self::fixed_vrate).collect();
+            let vrate = match Self::pick_adaptive_vrate(&hinges, &probed) {
+                Some(v) => v,
+                None => {
+                    info!(
+                        "iocost-qos: Adaptive sampling done, all hinges {:?} are probed",
+                        &hinges
+                    );
+                    break;
+                }
+            };
+
+            let mut ovr = IoCostQoSOvr {
+                min: Some(vrate),
+                max: Some(vrate),
+                ..Default::default()
+            };
+            ovr.sanitize();
+            if !self.ign_min_perf {
+                ovr.skip_or_adj(abs_min_vrate);
+                if ovr.skip {
+                    info!(
+                        "iocost-qos: Adaptive sampling stopped, vrate {:.2} is too low",
+                        vrate
+                    );
+                    break;
+                }
+            }
+
+            let i = self.runs.len();
+            let qos_cfg = IoCostQoSCfg::new(&bench_knobs.iocost.qos, &ovr);
+            info!(
+                "iocost-qos[{:02}]: Adaptive sampling around hinges {:?}, probing vrate {:.2}",
+                i, &hinges, vrate
+            );
+            info!("iocost-qos[{:02}]: {}", i, qos_cfg.format());
+            self.runs.push(ovr.clone());
+
+            match self.run_ovr(rctx, i, &qos_cfg, prev_rec) {
+                Ok(recr) => runs.push(Some(recr)),
+                Err(e) => {
+                    if !self.allow_fail || prog_exiting() {
+                        error!("iocost-qos[{:02}]: Failed ({:#}), giving up...", i, &e);
+                        return Err(e);
+                    }
+                    error!("iocost-qos[{:02}]: Failed ({:#}), skipping...", i, &e);
+                    runs.push(None);
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn study_one(
         &self,
         rctx: &mut RunCtx,
@@ -569,28 +857,8 @@ impl Job for IoCostQoSJob {
             info!("iocost-qos[{:02}]: {}", i, qos_cfg.format());
 
             loop {
-                let mut sjob = self.stor_job.clone();
-                sjob.loops = match i {
-                    0 => self.stor_base_loops,
-                    _ => self.stor_loops,
-                };
-                let mut pjob = self.prot_job.clone();
-
-                match Self::run_one(rctx, &mut sjob, &mut pjob, &qos_cfg, self.retries) {
+                match self.run_ovr(rctx, i, &qos_cfg, &mut prev_rec) {
                     Ok(recr) => {
-                        // Sanity check QoS params.
-                        if recr.qos.is_some() {
-                            let target_qos = qos_cfg.calc();
-                            if recr.qos != target_qos {
-                                bail!(
-                                    "iocost-qos: result qos ({}) != target qos ({})",
-                                    &recr.qos.as_ref().unwrap(),
-                                    target_qos.as_ref().unwrap(),
-                                );
-                            }
-                        }
-                        prev_rec.inc_runs.push(recr.clone());
-                        rctx.update_incremental_record(serde_json::to_value(&prev_rec).unwrap());
                         runs.push(Some(recr));
                         break;
                     }
@@ -610,6 +878,10 @@ impl Job for IoCostQoSJob {
         // configured number of runs.
         runs.resize(self.runs.len(), None);
 
+        if self.adaptive {
+            self.run_adaptive(rctx, &bench_knobs, prev_matches, &mut prev_rec, &mut runs)?;
+        }
+
         Ok(serde_json::to_value(&IoCostQoSRecord {
             base_model: bench_knobs.iocost.model,
             base_qos: bench_knobs.iocost.qos,
@@ -777,7 +1049,22 @@ impl Job for IoCostQoSJob {
             writeln!(out, "").unwrap();
         }
 
-        for (i, ovr) in self.runs.iter().enumerate() {
+        // Adaptive sampling appends runs which aren't in self.runs, take
+        // their configs from the record.
+        let ovrs: Vec<Option<&IoCostQoSOvr>> = if rec.runs.len() > self.runs.len() {
+            rec.runs
+                .iter()
+                .enumerate()
+                .map(|(i, recr)| recr.as_ref().map(|recr| &recr.ovr).or(self.runs.get(i)))
+                .collect()
+        } else {
+            self.runs.iter().map(Some).collect()
+        };
+        for (i, ovr) in ovrs.into_iter().enumerate() {
+            let ovr = match ovr {
+                Some(ovr) => ovr,
+                None => continue,
+            };
             let qos_cfg = IoCostQoSCfg::new(&rec.base_qos, ovr);
             write!(out, "[{:02}] QoS: {}", i, qos_cfg.format()).unwrap();
             if ovr.off {
@@ -880,3 +1167,66 @@ impl Job for IoCostQoSJob {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::IoCostQoSJob;
+    use resctl_bench_intf::Args;
+
+    #[test]
+    fn test_iocost_qos_pick_adaptive_vrate() {
+        let probed = [20.0, 40.0, 60.0, 80.0, 100.0];
+
+        // Unprobed hinge is picked as-is.
+        assert_eq!(
+            IoCostQoSJob::pick_adaptive_vrate(&[50.0], &probed),
+            Some(50.0)
+        );
+        // Probed hinges are bisected towards the wider side.
+        assert_eq!(
+            IoCostQoSJob::pick_adaptive_vrate(&[60.0], &probed),
+            Some(50.0)
+        );
+        assert_eq!(
+            IoCostQoSJob::pick_adaptive_vrate(&[61.0, 30.0], &probed),
+            Some(30.0)
+        );
+        // Nothing far enough from the probed vrates.
+        assert_eq!(
+            IoCostQoSJob::pick_adaptive_vrate(&[60.0], &[59.0, 59.5, 60.0, 60.5, 61.0]),
+            None
+        );
+        assert_eq!(IoCostQoSJob::pick_adaptive_vrate(&[], &probed), None);
+    }
+
+    #[test]
+    fn test_iocost_qos_adaptive_run_count() {
+        for (vrate_min, vrate_max, intvs) in [
+            (0.0, 100.0, 5),
+            (0.0, 125.0, 10),
+            (0.0, 100.0, 1),
+            (0.0, 100.0, 2),
+            (50.0, 100.0, 2),
+            (50.0, 100.0, 6),
+            (10.0, 125.0, 25),
+        ] {
+            let fixed = IoCostQoSJob::sweep_vrates(vrate_min, vrate_max, intvs);
+            let coarse_intvs = IoCostQoSJob::adaptive_sweep_intvs(intvs);
+            let coarse = IoCostQoSJob::sweep_vrates(vrate_min, vrate_max, coarse_intvs);
+            let spec = format!(
+                "iocost-qos:vrate-min={},vrate-max={},vrate-intvs={},adaptive",
+                vrate_min, vrate_max, intvs
+            );
+            let job = IoCostQoSJob::parse(&Args::parse_job_spec(&spec).unwrap(), None).unwrap();
+
+            // The coarse sweep plus the adaptive runs don't take more
+            // runs than the fixed sweep.
+            assert_eq!(job.runs.len() - 1, coarse.len(), "{}", &spec);
+            assert!(coarse.len() <= fixed.len(), "{}", &spec);
+            assert_eq!(coarse.len() + job.adaptive_runs, fixed.len(), "{}", &spec);
+            if intvs >= 4 {
+                assert!(job.adaptive_runs > 0, "{}", &spec);
+            }
+        }
+    }
+}
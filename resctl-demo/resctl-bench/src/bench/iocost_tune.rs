@@ -5,6 +5,9 @@ use super::iocost_qos::{
 use super::protection::mem_hog_tune::{DFL_ISOL_PCT, DFL_ISOL_THR};
 use super::protection::MemHog;
 use super::*;
+use crate::model_name;
+use confidence::{LinesInterval, SolutionConfidence};
+use fit::FitModel;
 use log::{debug, error};
 use scan_fmt::scan_fmt;
 use statrs::distribution::{ContinuousCDF, Normal};
@@ -14,13 +17,17 @@ use std::io::Write as IoWrite;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
+mod confidence;
+mod fit;
 mod graph;
 mod merge;
+mod sensitivity;
 
 const DFL_IOCOST_QOS_VRATE_MAX: f64 = 125.0;
 const DFL_IOCOST_QOS_VRATE_INTVS: u32 = 25;
 const DFL_SCALE_MIN: f64 = 1.0;
 const DFL_SCALE_MAX: f64 = 100.0;
+const DFL_BOOTSTRAP: u32 = 0;
 
 lazy_static::lazy_static! {
     static ref DFL_QOS_SPEC_STR: String = format!(
@@ -381,6 +388,90 @@ impl<'de> serde::de::Deserialize<'de> for DataSel {
     }
 }
 
+// Limit on a data series for QoSTarget::Constrained. Latencies are in
+// seconds like the data series.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+enum QoSLimit {
+    AtMost(f64),
+    AtLeast(f64),
+}
+
+impl QoSLimit {
+    fn parse(sel: &DataSel, input: &str) -> Result<Self> {
+        let (at_most, val) = match input.get(..2) {
+            Some("<=") => (true, input[2..].trim()),
+            Some(">=") => (false, input[2..].trim()),
+            _ => bail!("Limit {:?} is not <=VAL or >=VAL", input),
+        };
+        let val = match sel {
+            // Latencies are in usecs unless the unit is specified.
+            DataSel::RLat(_, _) | DataSel::WLat(_, _) => match val.parse::<f64>() {
+                Ok(usecs) => usecs / 1_000_000.0,
+                Err(_) => parse_duration(val)?,
+            },
+            _ => parse_frac(val)?,
+        };
+        Ok(if at_most {
+            Self::AtMost(val)
+        } else {
+            Self::AtLeast(val)
+        })
+    }
+
+    fn format(&self, sel: &DataSel) -> String {
+        let (op, val) = match self {
+            Self::AtMost(val) => ("<=", *val),
+            Self::AtLeast(val) => (">=", *val),
+        };
+        match sel {
+            DataSel::RLat(_, _) | DataSel::WLat(_, _) => {
+                format!("{}{}{}", sel, op, format_duration(val))
+            }
+            _ => format!("{}{}{}", sel, op, val),
+        }
+    }
+
+    /// The vrate ranges within @range where @dl satisfies the limit.
+    /// Nothing is known outside the range of @dl, so it never satisfies
+    /// the limit there.
+    fn ranges(&self, dl: &DataLines, range: (f64, f64)) -> Vec<(f64, f64)> {
+        let dl = match dl.clamped(range) {
+            Ok(Some(dl)) => dl,
+            _ => return vec![],
+        };
+        // Flip AtLeast so that we only need to handle "<=".
+        let (sign, limit) = match self {
+            Self::AtMost(val) => (1.0, *val),
+            Self::AtLeast(val) => (-1.0, -*val),
+        };
+        let pts: Vec<DataPoint> = dl
+            .points
+            .iter()
+            .map(|pt| DataPoint::new(pt.x, sign * pt.y))
+            .collect();
+
+        let mut ranges: Vec<(f64, f64)> = vec![];
+        let mut push = |r: (f64, f64)| match ranges.last_mut() {
+            Some(last) if last.1 >= r.0 => last.1 = r.1,
+            _ => ranges.push(r),
+        };
+        if pts.len() == 1 && pts[0].y <= limit {
+            push((pts[0].x, pts[0].x));
+        }
+        for pair in pts.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            let cross = || a.x + (limit - a.y) * (b.x - a.x) / (b.y - a.y);
+            match (a.y <= limit, b.y <= limit) {
+                (true, true) => push((a.x, b.x)),
+                (true, false) => push((a.x, cross())),
+                (false, true) => push((cross(), b.x)),
+                (false, false) => {}
+            }
+        }
+        ranges
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
 enum QoSTarget {
     VrateRange((f64, f64), (Option<String>, Option<String>)),
@@ -390,6 +481,8 @@ enum QoSTarget {
     AMOFDeltaMin,
     IsolatedBandwidth,
     LatRange(DataSel, (f64, f64)),
+    // Maximize the data series subject to the limits.
+    Constrained(DataSel, Vec<(DataSel, QoSLimit)>),
 }
 
 impl Default for QoSTarget {
@@ -434,6 +527,12 @@ impl std::fmt::Display for QoSTarget {
                 }
                 _ => panic!(),
             },
+            Self::Constrained(sel, limits) => {
+                write!(f, "{}=max", sel).unwrap();
+                for (sel, limit) in limits.iter() {
+                    write!(f, ", {}", limit.format(sel)).unwrap();
+                }
+            }
         }
         Ok(())
     }
@@ -504,6 +603,13 @@ impl QoSTarget {
             return Ok(Self::VrateRange(range, ref_pcts));
         }
 
+        let (limits, props): (BTreeMap<String, String>, BTreeMap<String, String>) = props
+            .into_iter()
+            .partition(|(_, v)| v.starts_with("<=") || v.starts_with(">="));
+        if limits.len() > 0 {
+            return Self::parse_constrained(props, limits);
+        }
+
         if props.len() != 1 {
             bail!("Each QoS rule should contain one QoS target");
         }
@@ -548,6 +654,27 @@ impl QoSTarget {
         }
     }
 
+    fn parse_constrained(
+        objective: BTreeMap<String, String>,
+        limits: BTreeMap<String, String>,
+    ) -> Result<QoSTarget> {
+        let sel = match objective.iter().next() {
+            Some((k, v)) if objective.len() == 1 && v.to_lowercase() == "max" => DataSel::parse(k)?,
+            _ => bail!("Limits should be combined with one \"mof=max\" or \"amof=max\""),
+        };
+        if sel != DataSel::MOF && sel != DataSel::AMOF {
+            bail!("Limits can't be combined with {:?}", &sel);
+        }
+
+        let mut parsed = vec![];
+        for (k, v) in limits.iter() {
+            let limit_sel = DataSel::parse(k)?;
+            let limit = QoSLimit::parse(&limit_sel, v)?;
+            parsed.push((limit_sel, limit));
+        }
+        Ok(Self::Constrained(sel, parsed))
+    }
+
     fn vrate_rpct_sel(pct: &str) -> DataSel {
         DataSel::RLat(pct.into(), "mean".into())
     }
@@ -574,6 +701,11 @@ impl QoSTarget {
             Self::AMOFDeltaMin => vec![DataSel::AMOFDelta],
             Self::IsolatedBandwidth => vec![DataSel::LatImp, DataSel::AMOF, DataSel::AMOFDelta],
             Self::LatRange(sel, _) => vec![sel.clone()],
+            Self::Constrained(sel, limits) => {
+                let mut sels = vec![sel.clone()];
+                sels.extend(limits.iter().map(|(sel, _)| sel.clone()));
+                sels
+            }
         }
     }
 
@@ -732,6 +864,54 @@ impl QoSTarget {
         }
     }
 
+    /// Find the vrate with the maximum value of @ds within @range where
+    /// all @limits are met. If the value is flat, the maximum vrate is
+    /// picked as there's nothing to lose as long as the limits are met.
+    fn solve_constrained(
+        ds: &DataSeries,
+        limits: &[(&DataSeries, &QoSLimit)],
+        range: (f64, f64),
+    ) -> Option<f64> {
+        let mut feasible = vec![range];
+        for (limit_ds, limit) in limits.iter() {
+            let ranges = limit.ranges(&limit_ds.lines, range);
+            let mut isect = vec![];
+            for a in feasible.iter() {
+                for b in ranges.iter() {
+                    let r = (a.0.max(b.0), a.1.min(b.1));
+                    if r.0 <= r.1 {
+                        isect.push(r);
+                    }
+                }
+            }
+            feasible = isect;
+        }
+
+        let mut best: Option<(f64, f64)> = None;
+        for r in feasible.iter() {
+            let dl = match ds.lines.clamped(*r).ok()? {
+                Some(dl) => dl,
+                None => continue,
+            };
+            let (min, max) = dl.min_max();
+            let vrate = if min == max {
+                dl.range.1
+            } else {
+                dl.points[Self::find_min_idx_for_y(&dl, max)?].x
+            };
+            trace!(
+                "solve_constrained range={:?} vrate={} val={}",
+                r,
+                vrate,
+                max
+            );
+            if best.is_none() || max >= best.unwrap().1 {
+                best = Some((vrate, max));
+            }
+        }
+        best.map(|(vrate, _)| vrate)
+    }
+
     fn solve(
         &self,
         data: &BTreeMap<DataSel, DataSeries>,
@@ -927,6 +1107,15 @@ impl QoSTarget {
                     None
                 }
             }
+
+            Self::Constrained(sel, limits) => {
+                let mut limit_dss = vec![];
+                for (limit_sel, limit) in limits.iter() {
+                    limit_dss.push((ds(limit_sel)?, limit));
+                }
+                Self::solve_constrained(ds(sel)?, &limit_dss, (scale_min, scale_max))
+                    .map(params_at_vrate)
+            }
         })
     }
 }
@@ -935,6 +1124,63 @@ impl QoSTarget {
 struct QoSRule {
     name: String,
     target: QoSTarget,
+    // Loaded from a rule file, included in the hwdb output.
+    #[serde(default)]
+    from_file: bool,
+}
+
+// Rule file values can be strings or numbers, e.g. `rpct = 50`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum QoSRuleFileValue {
+    Str(String),
+    Num(f64),
+}
+
+#[derive(Deserialize)]
+struct QoSRuleFile {
+    rule: Vec<BTreeMap<String, QoSRuleFileValue>>,
+}
+
+impl QoSRuleFile {
+    // Parses a TOML or, if the path ends with .json, JSON rule file into
+    // the same propsets that the second+ property groups produce.
+    fn parse(path: &str, text: &str) -> Result<Vec<BTreeMap<String, String>>> {
+        let file: QoSRuleFile = if path.ends_with(".json") {
+            serde_json::from_str(text)?
+        } else {
+            toml::from_str(text)?
+        };
+        let mut groups = vec![];
+        for props in file.rule.into_iter() {
+            let props: BTreeMap<String, String> = props
+                .into_iter()
+                .map(|(k, v)| match v {
+                    QoSRuleFileValue::Str(v) => (k, v),
+                    QoSRuleFileValue::Num(v) => (k, v.to_string()),
+                })
+                .collect();
+            // Rule names end up in hwdb property names and udev
+            // environment variables, keep them to a safe charset.
+            if let Some(name) = props.get("name") {
+                if name.is_empty()
+                    || !name
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+                {
+                    bail!("Invalid rule name {:?}, must be [a-z0-9-]+", name);
+                }
+            }
+            groups.push(props);
+        }
+        Ok(groups)
+    }
+
+    fn load(path: &str) -> Result<Vec<BTreeMap<String, String>>> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read rule file {:?}", path))?;
+        Self::parse(path, &text).with_context(|| format!("Failed to parse rule file {:?}", path))
+    }
 }
 
 #[derive(Debug)]
@@ -944,6 +1190,9 @@ struct IoCostTuneJob {
     scale_max: f64,
     sels: BTreeSet<DataSel>,
     rules: Vec<QoSRule>,
+    bootstrap: u32,
+    dfl_fit: FitModel,
+    fit_models: BTreeMap<DataSel, FitModel>,
 }
 
 impl Default for IoCostTuneJob {
@@ -954,6 +1203,9 @@ impl Default for IoCostTuneJob {
             scale_max: DFL_SCALE_MAX,
             sels: Default::default(),
             rules: Default::default(),
+            bootstrap: DFL_BOOTSTRAP,
+            dfl_fit: Default::default(),
+            fit_models: Default::default(),
         }
     }
 }
@@ -977,6 +1229,7 @@ impl Bench for IoCostTuneBench {
     fn parse(&self, spec: &JobSpec, _prev_data: Option<&JobData>) -> Result<Box<dyn Job>> {
         let mut job = IoCostTuneJob::default();
         let mut prop_groups = spec.props[1..].to_owned();
+        let mut file_groups = vec![];
 
         job.sels = [
             DataSel::MOF,
@@ -1006,6 +1259,14 @@ impl Bench for IoCostTuneBench {
             match k.as_str() {
                 "scale-min" => job.scale_min = parse_frac(v)? * 100.0,
                 "scale-max" => job.scale_max = parse_frac(v)? * 100.0,
+                "bootstrap" => job.bootstrap = v.parse::<u32>()?,
+                "rules" => file_groups = QoSRuleFile::load(v)?,
+                "fit" => job.dfl_fit = FitModel::parse(v)?,
+                k if k.starts_with("fit-") => {
+                    let sel = DataSel::parse(&k[4..])?;
+                    job.fit_models.insert(sel.clone(), FitModel::parse(v)?);
+                    job.sels.insert(sel);
+                }
                 k => {
                     let sel = DataSel::parse(k)?;
                     if v.len() > 0 {
@@ -1044,7 +1305,10 @@ impl Bench for IoCostTuneBench {
             push_props(&[("name", "rlat-99-q4"), ("rlat-99", "q4")]);
         }
 
-        for props in prop_groups.iter() {
+        let nr_cmdline = prop_groups.len();
+        prop_groups.append(&mut file_groups);
+
+        for (idx, props) in prop_groups.iter().enumerate() {
             let mut rule = QoSRule::default();
             let mut props = props.clone();
 
@@ -1054,13 +1318,20 @@ impl Bench for IoCostTuneBench {
                 bail!("Each rule must have a name");
             }
 
-            let target = QoSTarget::parse(props)?;
+            let target = QoSTarget::parse(props)
+                .with_context(|| format!("Parsing rule {:?}", &rule.name))?;
 
             for sel in target.sels().into_iter() {
                 job.sels.insert(sel);
             }
             rule.target = target;
-            job.rules.push(rule);
+            rule.from_file = idx >= nr_cmdline;
+
+            // A rule file rule replaces the earlier rule with the same name.
+            match job.rules.iter_mut().find(|r| r.name == rule.name) {
+                Some(prev) if rule.from_file => *prev = rule,
+                _ => job.rules.push(rule),
+            }
         }
 
         Ok(Box::new(job))
@@ -1078,8 +1349,8 @@ impl Bench for IoCostTuneBench {
         Some(format_job_props(&qos_props))
     }
 
-    fn merge(&self, srcs: &mut Vec<MergeSrc>) -> Result<JobData> {
-        merge::merge(srcs)
+    fn merge(&self, args: &Args, srcs: &mut Vec<MergeSrc>) -> Result<JobData> {
+        merge::merge(srcs, args.merge_bootstrap)
     }
 
     fn doc<'a>(&self, out: &mut Box<dyn Write + 'a>) -> Result<()> {
@@ -1279,6 +1550,10 @@ struct DataSeries {
     outliers: Vec<DataPoint>,
     lines: DataLines,
     error: f64,
+    #[serde(default)]
+    interval: Option<LinesInterval>,
+    #[serde(default)]
+    fit: FitModel,
 }
 
 impl DataSeries {
@@ -1611,6 +1886,17 @@ impl DataSeries {
         }
     }
 
+    fn fit(&mut self, model: FitModel, shape: DataShape) -> Result<()> {
+        self.fit = model;
+        match fit::fit(model, &self.data, shape) {
+            Some(lines) => {
+                self.lines = lines;
+                Ok(())
+            }
+            None => self.fit_lines(shape),
+        }
+    }
+
     fn fit_lines(&mut self, shape: DataShape) -> Result<()> {
         if self.data.len() == 0 {
             return Ok(());
@@ -1632,8 +1918,9 @@ impl DataSeries {
         let mean = statistical::mean(&self.data.iter().map(|p| p.y).collect::<Vec<f64>>());
         let range = Self::range(&self.data);
         // The correct output on a single datapoint is mean flat line going
-        // through the single data point.
-        if self.data.len() == 1 {
+        // through the single data point. The same goes for multiple data
+        // points at the same vrate, which bootstrap resamples can produce.
+        if range.0 == range.1 {
             self.lines = DataLines::new(&[DataPoint::new(range.0, mean)]).unwrap();
             return Ok(());
         }
@@ -2008,6 +2295,12 @@ pub struct IoCostTuneResult {
     isol_thr: f64,
     data: BTreeMap<DataSel, DataSeries>,
     solutions: BTreeMap<String, QoSSolution>,
+    #[serde(default)]
+    confidence: BTreeMap<String, SolutionConfidence>,
+    // Rules loaded from the rule file so that their solutions can be
+    // formatted without the file.
+    #[serde(default)]
+    file_rules: Vec<QoSRule>,
     remarks: Vec<String>,
 }
 
@@ -2095,7 +2388,8 @@ impl IoCostTuneJob {
             }
         }
 
-        series.fit_lines(shape)?;
+        let model = self.fit_models.get(sel).cloned().unwrap_or(self.dfl_fit);
+        series.fit(model, shape)?;
 
         if let Some(fill_upto) = fill_upto {
             series.lines = series
@@ -2114,7 +2408,7 @@ impl IoCostTuneJob {
                 &shape
             );
             let range = series.lines.range;
-            series.fit_lines(shape)?;
+            series.fit(model, shape)?;
             series.lines = series.lines.with_range(range).unwrap();
         }
 
@@ -2131,6 +2425,36 @@ impl IoCostTuneJob {
         Ok(())
     }
 
+    // The vrate range of a solution is relative to the scaled model.
+    fn scale_qos(qos: &mut IoCostQoSParams, scale_factor: f64) {
+        qos.min /= scale_factor;
+        qos.max /= scale_factor;
+        qos.sanitize();
+    }
+
+    fn solve_data(&self, data: &mut BTreeMap<DataSel, DataSeries>, isol_thr: f64) -> Result<()> {
+        // isol may be used in solving other data series, solve it first. We
+        // take it out of @data to avoid conflict with the mutable
+        // iteration below.
+        let isol_series = match data.remove(&DataSel::Isol) {
+            Some(mut series) => {
+                self.solve_data_series(&DataSel::Isol, &mut series, None, 0.0)?;
+                Some(series)
+            }
+            None => None,
+        };
+
+        for (sel, series) in data.iter_mut() {
+            self.solve_data_series(sel, series, isol_series.as_ref(), isol_thr)?;
+        }
+
+        // We're done solving. Put the isol series back in.
+        if let Some(isol_series) = isol_series {
+            data.insert(DataSel::Isol, isol_series);
+        }
+        Ok(())
+    }
+
     fn remark_on_lat(
         &self,
         rw: usize,
@@ -2335,15 +2659,38 @@ impl IoCostTuneJob {
 
         self.format_datapoints_summary(out, res);
 
+        let file_rules: Vec<&str> = res
+            .file_rules
+            .iter()
+            .map(|rule| rule.name.as_str())
+            .collect();
+        let in_hwdb = |name: &str| {
+            DEFAULT_HWDB_MODELS.contains(&name)
+                || name.starts_with("rlat")
+                || file_rules.contains(&name)
+        };
+
+        let low_confidence: Vec<&str> = res
+            .solutions
+            .keys()
+            .filter(|name| {
+                in_hwdb(name.as_str()) && res.confidence.get(*name).map(|c| c.low).unwrap_or(false)
+            })
+            .map(|name| name.as_str())
+            .collect();
+        if !low_confidence.is_empty() {
+            writeln!(out, "# low confidence: {}", low_confidence.join(" ")).unwrap();
+        }
+
         let sysrep = data.sysinfo.sysreqs_report.as_ref().unwrap();
         writeln!(
             out,
             "block:*:name:{}:fwrev:{}:",
-            sysrep.scr_dev_model,
+            model_name::hwdb_key(&sysrep.scr_dev_model),
             if single_fwrev {
-                &sysrep.scr_dev_fwrev
+                model_name::hwdb_key(&sysrep.scr_dev_fwrev)
             } else {
-                "*"
+                "*".into()
             }
         )
         .unwrap();
@@ -2366,10 +2713,10 @@ impl IoCostTuneJob {
         )
         .unwrap();
 
-        // Now add the rlat solutions, they should not be present in the list of defaults
-        // but should be included in the file.
+        // Now add the rlat and rule file solutions, they should not be present in the
+        // list of defaults but should be included in the file.
         for (name, solution) in res.solutions.iter() {
-            if !name.starts_with("rlat") {
+            if !in_hwdb(name.as_str()) || DEFAULT_HWDB_MODELS.contains(&name.as_str()) {
                 continue;
             }
 
@@ -2422,6 +2769,64 @@ impl IoCostTuneJob {
         }
     }
 
+    fn format_one_fit<'a>(
+        out: &mut Box<dyn Write + 'a>,
+        rules: &[&QoSRule],
+        data: &BTreeMap<DataSel, DataSeries>,
+    ) {
+        let sels: BTreeSet<DataSel> = rules
+            .iter()
+            .flat_map(|rule| rule.target.sels().into_iter())
+            .collect();
+        let fits: Vec<String> = sels
+            .iter()
+            .filter_map(|sel| {
+                let ds = data.get(sel)?;
+                Some(format!(
+                    "{}={}(R^2={:.3})",
+                    sel,
+                    ds.fit,
+                    fit::r_squared(&ds.data, &ds.lines)
+                ))
+            })
+            .collect();
+        if !fits.is_empty() {
+            writeln!(out, "  fit: {}", fits.join(" ")).unwrap();
+        }
+    }
+
+    fn format_one_confidence<'a>(out: &mut Box<dyn Write + 'a>, conf: &SolutionConfidence) {
+        if conf.resamples == 0 {
+            writeln!(out, "  conf: too few runs, low confidence").unwrap();
+            return;
+        }
+
+        let lat_intv = |intv: &confidence::Interval| {
+            format!(
+                "{}-{}",
+                format_duration(intv.low / 1_000_000.0),
+                format_duration(intv.high / 1_000_000.0)
+            )
+        };
+        writeln!(
+            out,
+            "  conf: {:.0}% vrate={:.1}-{:.1} MOF={:.3}-{:.3} aMOF={:.3}-{:.3} rlat={} wlat={} solved={}/{}{}",
+            confidence::LEVEL * 100.0,
+            conf.vrate.low,
+            conf.vrate.high,
+            conf.mof.low,
+            conf.mof.high,
+            conf.amof.low,
+            conf.amof.high,
+            lat_intv(&conf.rlat),
+            lat_intv(&conf.wlat),
+            conf.solved,
+            conf.resamples,
+            if conf.low { ", low confidence" } else { "" }
+        )
+        .unwrap();
+    }
+
     fn format_one_solution<'a>(out: &mut Box<dyn Write + 'a>, sol: &QoSSolution, isol_pct: &str) {
         let model = &sol.model;
         let qos = &sol.qos;
@@ -2483,8 +2888,43 @@ impl IoCostTuneJob {
         .unwrap();
     }
 
+    // The result may have been solved with a rule file the job wasn't
+    // parsed with.
+    fn all_rules<'a>(&'a self, res: &'a IoCostTuneResult) -> Vec<&'a QoSRule> {
+        let mut all_rules: Vec<&QoSRule> = self.rules.iter().collect();
+        for rule in res.file_rules.iter() {
+            if !self.rules.iter().any(|r| r.name == rule.name) {
+                all_rules.push(rule);
+            }
+        }
+        all_rules
+    }
+
+    fn format_sensitivity<'a>(
+        &self,
+        out: &mut Box<dyn Write + 'a>,
+        res: &IoCostTuneResult,
+        shift: f64,
+    ) -> Result<()> {
+        let sensitivities = sensitivity::analyze(self, res, &self.all_rules(res), shift)?;
+
+        write!(out, "{}\n", &double_underline("Sensitivity")).unwrap();
+        writeln!(
+            out,
+            "Each benchmark run left out and its values shifted by +-{}%\n",
+            format_pct(shift)
+        )
+        .unwrap();
+        for sens in sensitivities.iter() {
+            sens.format(out);
+            writeln!(out, "").unwrap();
+        }
+        Ok(())
+    }
+
     fn format_solutions<'a>(&self, out: &mut Box<dyn Write + 'a>, res: &IoCostTuneResult) {
-        if self.rules.len() == 0 {
+        let all_rules = self.all_rules(res);
+        if all_rules.len() == 0 {
             return;
         }
 
@@ -2496,7 +2936,26 @@ impl IoCostTuneJob {
             if rules.len() > 0 {
                 Self::format_rules(out, &rules);
                 match prev_sol {
-                    Some(prev_sol) => Self::format_one_solution(out, prev_sol, &res.isol_pct),
+                    Some(prev_sol) => {
+                        Self::format_one_solution(out, prev_sol, &res.isol_pct);
+                        Self::format_one_fit(out, &rules, &res.data);
+                        // Solutions are grouped when they're equal but
+                        // their confidence may differ. Report the least
+                        // confident one.
+                        if let Some(conf) = rules
+                            .iter()
+                            .filter_map(|rule| res.confidence.get(&rule.name))
+                            .max_by(|a, b| {
+                                a.low.cmp(&b.low).then(
+                                    (a.vrate.high - a.vrate.low)
+                                        .partial_cmp(&(b.vrate.high - b.vrate.low))
+                                        .unwrap(),
+                                )
+                            })
+                        {
+                            Self::format_one_confidence(out, conf);
+                        }
+                    }
                     None => writeln!(out, "  NO SOLUTION").unwrap(),
                 }
                 writeln!(out, "").unwrap();
@@ -2504,7 +2963,7 @@ impl IoCostTuneJob {
             }
         };
 
-        for rule in self.rules.iter() {
+        for rule in all_rules.into_iter() {
             let sol = res.solutions.get(&rule.name);
             if !rules.is_empty()
                 && !(sol.is_none() && prev_sol.is_none())
@@ -2609,6 +3068,40 @@ impl IoCostTuneJob {
     }
 }
 
+/// Used by iocost-qos adaptive sampling. Fit the MOF, aMOF and isolation
+/// data series of the partial @qrec and @qres the same way iocost-tune does
+/// and return the vrates where the fitted lines have hinges, in ascending
+/// order. These are where the solutions land and thus where more data
+/// points are the most useful.
+pub(super) fn qos_hinge_vrates(
+    qrec: &IoCostQoSRecord,
+    qres: &IoCostQoSResult,
+    isol_pct: &str,
+    isol_thr: f64,
+) -> Result<Vec<f64>> {
+    let job = IoCostTuneJob::default();
+    let mut data = BTreeMap::<DataSel, DataSeries>::new();
+    for sel in [DataSel::MOF, DataSel::AMOF, DataSel::Isol].iter() {
+        data.insert(
+            sel.clone(),
+            IoCostTuneJob::collect_data_series(sel, qrec, qres, isol_pct)?,
+        );
+    }
+    job.solve_data(&mut data, isol_thr)?;
+
+    let mut vrates: Vec<f64> = data
+        .values()
+        .filter(|ds| ds.lines.points.len() > 2)
+        .flat_map(|ds| {
+            let pts = &ds.lines.points;
+            pts[1..pts.len() - 1].iter().map(|pt| pt.x)
+        })
+        .collect();
+    vrates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    vrates.dedup();
+    Ok(vrates)
+}
+
 impl Job for IoCostTuneJob {
     fn sysreqs(&self) -> BTreeSet<SysReq> {
         IoCostQoSJob::default().sysreqs()
@@ -2680,6 +3173,8 @@ impl Job for IoCostTuneJob {
             isol_thr,
             data,
             solutions: Default::default(),
+            confidence: Default::default(),
+            file_rules: Default::default(),
             remarks: Default::default(),
         })?)
     }
@@ -2698,25 +3193,7 @@ impl Job for IoCostTuneJob {
         }
         res.solutions = Default::default();
 
-        // isol may be used in solving other data series, solve it first. We
-        // take it out of @data to avoid conflict with the mutable
-        // iteration below.
-        let isol_series = match res.data.remove(&DataSel::Isol) {
-            Some(mut series) => {
-                self.solve_data_series(&DataSel::Isol, &mut series, None, 0.0)?;
-                Some(series)
-            }
-            None => None,
-        };
-
-        for (sel, series) in res.data.iter_mut() {
-            self.solve_data_series(sel, series, isol_series.as_ref(), res.isol_thr)?;
-        }
-
-        // We're done solving. Put the isol series back in.
-        if let Some(isol_series) = isol_series {
-            res.data.insert(DataSel::Isol, isol_series);
-        }
+        self.solve_data(&mut res.data, res.isol_thr)?;
 
         for rule in self.rules.iter() {
             trace!(
@@ -2743,9 +3220,7 @@ impl Job for IoCostTuneJob {
                 );
                 let scale_factor = target_vrate / 100.0;
                 let model = res.base_model.clone() * scale_factor;
-                qos.min /= scale_factor;
-                qos.max /= scale_factor;
-                qos.sanitize();
+                Self::scale_qos(&mut qos, scale_factor);
 
                 res.solutions.insert(
                     rule.name.clone(),
@@ -2762,6 +3237,13 @@ impl Job for IoCostTuneJob {
             }
         }
 
+        confidence::bootstrap(self, &mut res)?;
+        res.file_rules = self
+            .rules
+            .iter()
+            .filter(|rule| rule.from_file)
+            .cloned()
+            .collect();
         res.remarks = self.remarks(&res);
 
         Ok(serde_json::to_value(res)?)
@@ -2781,6 +3263,7 @@ impl Job for IoCostTuneJob {
         let mut high_level = false;
         let mut hwdb = false;
         let mut hwdb_fwrev = false;
+        let mut sensitivity = None;
         for (k, v) in props[0].iter() {
             match k.as_ref() {
                 "pdf" => {
@@ -2799,11 +3282,18 @@ impl Job for IoCostTuneJob {
                 "high-level" => high_level = v.len() == 0 || v.parse::<bool>()?,
                 "hwdb" => hwdb = v.len() == 0 || v.parse::<bool>()?,
                 "hwdb-fwrev" => hwdb_fwrev = v.len() == 0 || v.parse::<bool>()?,
+                "sensitivity" => {
+                    sensitivity = Some(if v.len() > 0 {
+                        parse_frac(v)?
+                    } else {
+                        sensitivity::DFL_SHIFT
+                    })
+                }
                 k => bail!("unknown format parameter {:?}", k),
             }
         }
 
-        if pdf_path.is_some() && (high_level || hwdb) {
+        if pdf_path.is_some() && (high_level || hwdb || sensitivity.is_some()) {
             bail!("format parameters are incompatible with each other")
         }
 
@@ -2819,6 +3309,10 @@ impl Job for IoCostTuneJob {
             return Ok(());
         }
 
+        if let Some(shift) = sensitivity {
+            return self.format_sensitivity(out, &res, shift);
+        }
+
         let vrate_range = res
             .data
             .iter()
@@ -2854,7 +3348,7 @@ impl Job for IoCostTuneJob {
 
 #[cfg(test)]
 mod tests {
-    use super::{DataLines, DataPoint, DataSel, DataSeries, QoSTarget};
+    use super::{DataLines, DataPoint, DataSel, DataSeries, QoSLimit, QoSRuleFile, QoSTarget};
 
     #[test]
     fn test_bench_iocost_tune_datasel_sort_and_group() {
@@ -3044,4 +3538,111 @@ mod tests {
             assert!(sol <= *sol_max);
         }
     }
+
+    #[test]
+    fn test_qos_rule_file() {
+        let toml = r#"
+            [[rule]]
+            name = "p95-latency-capped"
+            rlat-95 = "q2"
+
+            [[rule]]
+            name = "fixed"
+            vrate = "50-75"
+            rpct = 50
+        "#;
+        let json = r#"{"rule": [
+            {"name": "p95-latency-capped", "rlat-95": "q2"},
+            {"name": "fixed", "vrate": "50-75", "rpct": 50}
+        ]}"#;
+
+        let groups = QoSRuleFile::parse("rules.toml", toml).unwrap();
+        assert_eq!(groups, QoSRuleFile::parse("rules.json", json).unwrap());
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0]["name"], "p95-latency-capped");
+        assert_eq!(groups[1]["rpct"], "50");
+
+        let mut props = groups[1].clone();
+        props.remove("name");
+        assert_eq!(
+            QoSTarget::parse(props).unwrap(),
+            QoSTarget::VrateRange((50.0, 75.0), (Some("50".to_owned()), Some("99".to_owned())))
+        );
+
+        assert!(QoSRuleFile::parse("rules.toml", "rule = 1").is_err());
+        for name in [
+            "",
+            "Upper",
+            "under_score",
+            "sp ace",
+            "semi;colon",
+            "dollar$",
+        ] {
+            let toml = format!("[[rule]]\nname = {:?}\nvrate = \"50-75\"\n", name);
+            assert!(
+                QoSRuleFile::parse("rules.toml", &toml).is_err(),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_qos_target_constrained() {
+        let props = |kvs: &[(&str, &str)]| {
+            kvs.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let rlat_99 = DataSel::RLat("99".to_owned(), "mean".to_owned());
+        let isol_05 = DataSel::IsolPct("05".to_owned());
+
+        assert_eq!(
+            QoSTarget::parse(props(&[
+                ("amof", "max"),
+                ("rlat-99-mean", "<=5000"),
+                ("isol-05", ">=90%")
+            ]))
+            .unwrap(),
+            QoSTarget::Constrained(
+                DataSel::AMOF,
+                vec![
+                    (isol_05.clone(), QoSLimit::AtLeast(0.9)),
+                    (rlat_99.clone(), QoSLimit::AtMost(0.005))
+                ]
+            )
+        );
+        assert!(QoSTarget::parse(props(&[("rlat-99-mean", "<=5m")])).is_err());
+        assert!(QoSTarget::parse(props(&[("isol", "max"), ("rlat-99", "<=5m")])).is_err());
+
+        let lines = |pts: &[(f64, f64)]| DataSeries {
+            lines: DataLines::new(
+                &pts.iter()
+                    .map(|(x, y)| DataPoint::new(*x, *y))
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap(),
+            ..Default::default()
+        };
+        let amof = lines(&[(10.0, 1.0), (20.0, 1.0), (60.0, 2.0), (100.0, 2.0)]);
+        let rlat = lines(&[(10.0, 0.001), (50.0, 0.001), (100.0, 0.010)]);
+        let isol = lines(&[(10.0, 1.0), (30.0, 1.0), (80.0, 0.5), (100.0, 0.5)]);
+        let solve = |limits: &[(&DataSeries, &QoSLimit)]| {
+            QoSTarget::solve_constrained(&amof, limits, (1.0, 100.0)).unwrap()
+        };
+
+        let (loose, tight) = (QoSLimit::AtMost(0.005), QoSLimit::AtMost(0.0015));
+        assert_eq!(solve(&[(&rlat, &loose)]), 60.0);
+        assert!((solve(&[(&rlat, &tight)]) - 52.78).abs() < 0.01);
+        assert_eq!(
+            solve(&[(&rlat, &loose), (&isol, &QoSLimit::AtLeast(0.9))]),
+            40.0
+        );
+        assert!(QoSTarget::solve_constrained(
+            &amof,
+            &[(&rlat, &QoSLimit::AtMost(0.0005))],
+            (1.0, 100.0)
+        )
+        .is_none());
+    }
 }
@@ -0,0 +1,290 @@
+// Copyright (c) Facebook, Inc. and its affiliates.
+//! Bootstrap confidence intervals for iocost-tune solutions.
+//!
+//! The benchmark runs are resampled with replacement and the data series
+//! and solutions are solved again for each resample. A run is identified
+//! by its vrate, which is shared by the data points it contributed to all
+//! the data series, so the resamples keep the data series consistent with
+//! each other. For merged results, the runs come from all the sources and
+//! the intervals narrow as more results are merged.
+use super::super::*;
+use super::{DataLines, DataPoint, DataSel, DataSeries, IoCostTuneJob, IoCostTuneResult};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+
+/// Confidence level of the intervals.
+pub const LEVEL: f64 = 0.9;
+
+/// The resamples are seeded so that the same result always produces the
+/// same intervals.
+const SEED: u64 = 0;
+
+/// The fitted lines intervals are evaluated at this many vrates.
+const NR_LINES_POINTS: usize = 11;
+
+/// With fewer runs, there isn't much to resample.
+const MIN_RUNS: usize = 4;
+
+/// A solution is low confidence if its vrate interval is wider than this
+/// fraction of its vrate or if it couldn't be solved in more than
+/// 1 - LOW_SOLVED_FRAC of the resamples.
+const LOW_VRATE_SPREAD: f64 = 0.25;
+const LOW_SOLVED_FRAC: f64 = 0.9;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub(super) struct Interval {
+    pub(super) low: f64,
+    pub(super) high: f64,
+}
+
+impl Interval {
+    fn from_samples(mut samples: Vec<f64>) -> Self {
+        if samples.is_empty() {
+            return Default::default();
+        }
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let last = (samples.len() - 1) as f64;
+        Self {
+            low: samples[(last * (1.0 - LEVEL) / 2.0).round() as usize],
+            high: samples[(last * (1.0 + LEVEL) / 2.0).round() as usize],
+        }
+    }
+}
+
+/// Lower and upper bounds of the fitted lines of a data series.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub(super) struct LinesInterval {
+    pub(super) low: DataLines,
+    pub(super) high: DataLines,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub(super) struct SolutionConfidence {
+    pub(super) resamples: u32,
+    /// The number of resamples which produced a solution.
+    pub(super) solved: u32,
+    pub(super) vrate: Interval,
+    pub(super) mof: Interval,
+    pub(super) amof: Interval,
+    /// QoS latency targets in usecs.
+    pub(super) rlat: Interval,
+    pub(super) wlat: Interval,
+    pub(super) low: bool,
+}
+
+impl SolutionConfidence {
+    fn is_low(&self, vrate: f64) -> bool {
+        self.resamples == 0
+            || (self.solved as f64) < self.resamples as f64 * LOW_SOLVED_FRAC
+            || self.vrate.high - self.vrate.low > vrate * LOW_VRATE_SPREAD
+    }
+}
+
+#[derive(Default)]
+struct SolutionSamples {
+    solved: u32,
+    vrate: Vec<f64>,
+    mof: Vec<f64>,
+    amof: Vec<f64>,
+    rlat: Vec<f64>,
+    wlat: Vec<f64>,
+}
+
+fn lines_xs(lines: &DataLines) -> Vec<f64> {
+    let (left, right) = lines.range;
+    if lines.points.is_empty() {
+        vec![]
+    } else if left == right {
+        vec![left]
+    } else {
+        (0..NR_LINES_POINTS)
+            .map(|i| left + (right - left) * i as f64 / (NR_LINES_POINTS - 1) as f64)
+            .collect()
+    }
+}
+
+/// The points of the data series in @data including the outliers, sorted,
+/// and the vrates of the benchmark runs they came from, sorted.
+pub(super) fn points_by_run<'a>(
+    data: impl Iterator<Item = (&'a DataSel, &'a DataSeries)>,
+) -> (BTreeMap<DataSel, Vec<DataPoint>>, Vec<f64>) {
+    let points: BTreeMap<DataSel, Vec<DataPoint>> = data
+        .map(|(sel, ds)| {
+            let mut pts: Vec<DataPoint> =
+                ds.data.iter().chain(ds.outliers.iter()).cloned().collect();
+            pts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            (sel.clone(), pts)
+        })
+        .collect();
+
+    let mut runs: Vec<f64> = points.values().flatten().map(|pt| pt.x).collect();
+    runs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    runs.dedup();
+
+    (points, runs)
+}
+
+/// Rebuild the data series from @points and @runs as returned by
+/// points_by_run() and solve them with @job. @perturb is called with the
+/// index of the run and the point and returns how many times to add
+/// which point in its place. Fails if the program is exiting, so that long
+/// analyses can be interrupted.
+pub(super) fn solve_perturbed(
+    job: &IoCostTuneJob,
+    isol_thr: f64,
+    points: &BTreeMap<DataSel, Vec<DataPoint>>,
+    runs: &[f64],
+    perturb: impl Fn(usize, &DataPoint) -> (usize, DataPoint),
+) -> Result<BTreeMap<DataSel, DataSeries>> {
+    if prog_exiting() {
+        bail!("Program exiting");
+    }
+
+    let mut data: BTreeMap<DataSel, DataSeries> = BTreeMap::new();
+    for (sel, pts) in points.iter() {
+        let mut ds = DataSeries::default();
+        for pt in pts.iter() {
+            let idx = runs
+                .binary_search_by(|x| x.partial_cmp(&pt.x).unwrap())
+                .unwrap();
+            let (count, pt) = perturb(idx, pt);
+            for _ in 0..count {
+                ds.data.push(pt);
+            }
+        }
+        data.insert(sel.clone(), ds);
+    }
+
+    job.solve_data(&mut data, isol_thr)?;
+    Ok(data)
+}
+
+/// Fill in the lines intervals of @res.data and the confidence of
+/// @res.solutions which must have been solved by @job.
+pub(super) fn bootstrap(job: &IoCostTuneJob, res: &mut IoCostTuneResult) -> Result<()> {
+    res.confidence = Default::default();
+    for ds in res.data.values_mut() {
+        ds.interval = None;
+    }
+    if job.bootstrap == 0 {
+        return Ok(());
+    }
+
+    let (points, runs) = points_by_run(res.data.iter());
+    if runs.len() < MIN_RUNS {
+        for name in res.solutions.keys() {
+            res.confidence.insert(
+                name.clone(),
+                SolutionConfidence {
+                    low: true,
+                    ..Default::default()
+                },
+            );
+        }
+        return Ok(());
+    }
+
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut lines_samples: BTreeMap<DataSel, Vec<Vec<f64>>> = BTreeMap::new();
+    let mut sol_samples: BTreeMap<&str, SolutionSamples> = BTreeMap::new();
+
+    for _ in 0..job.bootstrap {
+        let mut counts = vec![0; runs.len()];
+        for _ in 0..runs.len() {
+            counts[rng.gen_range(0..runs.len())] += 1;
+        }
+
+        let data = solve_perturbed(job, res.isol_thr, &points, &runs, |idx, pt| {
+            (counts[idx], *pt)
+        })?;
+
+        for (sel, ds) in res.data.iter() {
+            let xs = lines_xs(&ds.lines);
+            let samples = lines_samples
+                .entry(sel.clone())
+                .or_insert_with(|| vec![vec![]; xs.len()]);
+            for (x, samples) in xs.iter().zip(samples.iter_mut()) {
+                samples.push(data[sel].lines.eval(*x));
+            }
+        }
+
+        for rule in job.rules.iter() {
+            if !res.solutions.contains_key(&rule.name) {
+                continue;
+            }
+            let samples = sol_samples.entry(&rule.name).or_default();
+            if let Ok(Some((qos, target_vrate))) =
+                rule.target.solve(&data, (job.scale_min, job.scale_max))
+            {
+                samples.solved += 1;
+                samples.vrate.push(target_vrate);
+                samples
+                    .mof
+                    .push(data[&DataSel::MOF].lines.eval(target_vrate));
+                samples
+                    .amof
+                    .push(data[&DataSel::AMOF].lines.eval(target_vrate));
+                samples.rlat.push(qos.rlat as f64);
+                samples.wlat.push(qos.wlat as f64);
+            }
+        }
+    }
+
+    for (sel, samples) in lines_samples.into_iter() {
+        let xs = lines_xs(&res.data[&sel].lines);
+        let (mut low, mut high) = (vec![], vec![]);
+        for (x, samples) in xs.into_iter().zip(samples.into_iter()) {
+            let intv = Interval::from_samples(samples);
+            low.push(DataPoint::new(x, intv.low));
+            high.push(DataPoint::new(x, intv.high));
+        }
+        let interval = LinesInterval {
+            low: DataLines::new(&low)?,
+            high: DataLines::new(&high)?,
+        };
+        res.data.get_mut(&sel).unwrap().interval = Some(interval);
+    }
+
+    for (name, samples) in sol_samples.into_iter() {
+        let mut conf = SolutionConfidence {
+            resamples: job.bootstrap,
+            solved: samples.solved,
+            vrate: Interval::from_samples(samples.vrate),
+            mof: Interval::from_samples(samples.mof),
+            amof: Interval::from_samples(samples.amof),
+            rlat: Interval::from_samples(samples.rlat),
+            wlat: Interval::from_samples(samples.wlat),
+            low: false,
+        };
+        conf.low = conf.is_low(res.solutions[name].scale_factor * 100.0);
+        res.confidence.insert(name.to_string(), conf);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interval;
+
+    #[test]
+    fn test_iocost_tune_confidence_interval() {
+        assert_eq!(Interval::from_samples(vec![]), Interval::default());
+        assert_eq!(
+            Interval::from_samples(vec![3.0]),
+            Interval {
+                low: 3.0,
+                high: 3.0
+            }
+        );
+        let samples: Vec<f64> = (0..21).rev().map(|v| v as f64).collect();
+        assert_eq!(
+            Interval::from_samples(samples),
+            Interval {
+                low: 1.0,
+                high: 19.0
+            }
+        );
+    }
+}
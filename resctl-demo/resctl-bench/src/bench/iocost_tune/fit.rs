@@ -0,0 +1,344 @@
+// Copyright (c) Facebook, Inc. and its affiliates.
+//! Alternative models to fit iocost-tune data series with.
+//!
+//! DataSeries::fit_lines() fits at most a flat line, a slope and another
+//! flat line, which under-fits devices whose latencies bend gradually as
+//! vrate goes up. The models here follow the data more closely while
+//! still honoring the DataShape of the data series. The fitted curves are
+//! sampled into DataLines so that the solvers can use them unchanged.
+use super::super::*;
+use super::{DataLines, DataPoint, DataShape};
+
+/// Fraction of the data points used for each LOWESS local regression.
+const LOWESS_FRAC: f64 = 0.5;
+
+/// Number of line segments each monotone spline segment is sampled into.
+const SPLINE_STEPS: usize = 8;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(super) enum FitModel {
+    /// Up to three line segments, see DataSeries::fit_lines()
+    #[default]
+    Lines,
+    /// Weighted least squares fit honoring the DataShape
+    Isotonic,
+    /// Locally weighted linear regressions, made to honor the DataShape
+    Lowess,
+    /// Monotone cubic spline through the isotonic fit
+    MonotoneSpline,
+}
+
+impl FitModel {
+    pub(super) fn parse(input: &str) -> Result<Self> {
+        Ok(match input.to_lowercase().as_str() {
+            "lines" => Self::Lines,
+            "isotonic" => Self::Isotonic,
+            "lowess" => Self::Lowess,
+            "monotone-spline" | "spline" => Self::MonotoneSpline,
+            v => bail!(
+                "Unknown fitting model {:?}, supported: lines, isotonic, lowess, monotone-spline",
+                v
+            ),
+        })
+    }
+}
+
+impl std::fmt::Display for FitModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Lines => write!(f, "lines"),
+            Self::Isotonic => write!(f, "isotonic"),
+            Self::Lowess => write!(f, "lowess"),
+            Self::MonotoneSpline => write!(f, "monotone-spline"),
+        }
+    }
+}
+
+/// Average the points at the same vrate, which merged results and
+/// bootstrap resamples have. Returns (vrate, mean, nr_points) tuples.
+fn group_by_x(data: &[DataPoint]) -> Vec<(f64, f64, f64)> {
+    let mut groups: Vec<(f64, f64, f64)> = vec![];
+    for pt in data.iter() {
+        match groups.last_mut() {
+            Some(last) if last.0 == pt.x => {
+                last.1 += pt.y;
+                last.2 += 1.0;
+            }
+            _ => groups.push((pt.x, pt.y, 1.0)),
+        }
+    }
+    for group in groups.iter_mut() {
+        group.1 /= group.2;
+    }
+    groups
+}
+
+/// Pool adjacent violators. Weighted least squares non-decreasing fit.
+fn pava(ys: &[f64], ws: &[f64]) -> Vec<f64> {
+    // (mean, weight, nr_values) of each pooled block
+    let mut blocks: Vec<(f64, f64, usize)> = vec![];
+    for (y, w) in ys.iter().zip(ws.iter()) {
+        blocks.push((*y, *w, 1));
+        while blocks.len() > 1 && blocks[blocks.len() - 2].0 > blocks[blocks.len() - 1].0 {
+            let (b, a) = (blocks.pop().unwrap(), blocks.pop().unwrap());
+            let w = a.1 + b.1;
+            blocks.push(((a.0 * a.1 + b.0 * b.1) / w, w, a.2 + b.2));
+        }
+    }
+    blocks
+        .into_iter()
+        .flat_map(|(y, _, nr)| std::iter::repeat(y).take(nr))
+        .collect()
+}
+
+fn sse(ys: &[f64], ws: &[f64], fit: &[f64]) -> f64 {
+    ys.iter()
+        .zip(ws.iter())
+        .zip(fit.iter())
+        .map(|((y, w), f)| w * (y - f).powi(2))
+        .sum()
+}
+
+/// Weighted least squares fit of @ys which honors @shape.
+fn isotonic(ys: &[f64], ws: &[f64], shape: DataShape) -> Vec<f64> {
+    let neg = |vals: &[f64]| vals.iter().map(|v| -v).collect::<Vec<f64>>();
+    match shape {
+        DataShape::Any => ys.to_vec(),
+        DataShape::Inc => pava(ys, ws),
+        DataShape::Dec => neg(&pava(&neg(ys), ws)),
+        DataShape::SinglePeak => {
+            // Try every split between the rising and falling parts.
+            let mut best: Option<(f64, Vec<f64>)> = None;
+            for split in 0..ys.len() + 1 {
+                let mut fit = pava(&ys[..split], &ws[..split]);
+                fit.extend(neg(&pava(&neg(&ys[split..]), &ws[split..])));
+                let err = sse(ys, ws, &fit);
+                if best.as_ref().map_or(true, |(best_err, _)| err < *best_err) {
+                    best = Some((err, fit));
+                }
+            }
+            best.unwrap().1
+        }
+    }
+}
+
+/// LOWESS with tricube weights evaluated at @xs. The result is then made
+/// to honor @shape with isotonic().
+fn lowess(xs: &[f64], ys: &[f64], ws: &[f64], shape: DataShape) -> Vec<f64> {
+    let nr_near = ((xs.len() as f64 * LOWESS_FRAC).ceil() as usize).clamp(2, xs.len());
+    let mut smoothed = vec![];
+    for x in xs.iter() {
+        let mut dists: Vec<f64> = xs.iter().map(|v| (v - x).abs()).collect();
+        dists.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let h = dists[nr_near - 1];
+
+        let (mut sw, mut swx, mut swy, mut swxx, mut swxy) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for ((xi, yi), wi) in xs.iter().zip(ys.iter()).zip(ws.iter()) {
+            let d = (xi - x).abs() / h;
+            if d >= 1.0 {
+                continue;
+            }
+            let w = wi * (1.0 - d.powi(3)).powi(3);
+            sw += w;
+            swx += w * xi;
+            swy += w * yi;
+            swxx += w * xi * xi;
+            swxy += w * xi * yi;
+        }
+
+        let det = sw * swxx - swx * swx;
+        smoothed.push(if det.abs() > f64::EPSILON * sw * swxx {
+            let slope = (sw * swxy - swx * swy) / det;
+            (swy - slope * swx) / sw + slope * x
+        } else {
+            swy / sw
+        });
+    }
+    isotonic(&smoothed, ws, shape)
+        .into_iter()
+        .map(|v| v.max(0.0))
+        .collect()
+}
+
+/// Fritsch-Carlson monotone cubic interpolation through @knots sampled
+/// into line segments. Flat stretches between knots stay flat.
+fn monotone_spline(knots: &[DataPoint]) -> Vec<DataPoint> {
+    let nr = knots.len();
+    if nr < 3 {
+        return knots.to_vec();
+    }
+
+    let deltas: Vec<f64> = knots
+        .windows(2)
+        .map(|p| (p[1].y - p[0].y) / (p[1].x - p[0].x))
+        .collect();
+    let mut slopes = vec![0.0; nr];
+    slopes[0] = deltas[0];
+    slopes[nr - 1] = deltas[nr - 2];
+    for i in 1..nr - 1 {
+        if deltas[i - 1] * deltas[i] > 0.0 {
+            slopes[i] = (deltas[i - 1] + deltas[i]) / 2.0;
+        }
+    }
+    for i in 0..nr - 1 {
+        if deltas[i] == 0.0 {
+            slopes[i] = 0.0;
+            slopes[i + 1] = 0.0;
+            continue;
+        }
+        let (a, b) = (slopes[i] / deltas[i], slopes[i + 1] / deltas[i]);
+        let mag = a * a + b * b;
+        if mag > 9.0 {
+            let tau = 3.0 / mag.sqrt();
+            slopes[i] = tau * a * deltas[i];
+            slopes[i + 1] = tau * b * deltas[i];
+        }
+    }
+
+    let mut points = vec![knots[0]];
+    for i in 0..nr - 1 {
+        let (p0, p1) = (knots[i], knots[i + 1]);
+        let steps = if deltas[i] == 0.0 { 1 } else { SPLINE_STEPS };
+        let h = p1.x - p0.x;
+        for step in 1..steps + 1 {
+            let t = step as f64 / steps as f64;
+            let (t2, t3) = (t * t, t * t * t);
+            let y = (2.0 * t3 - 3.0 * t2 + 1.0) * p0.y
+                + (t3 - 2.0 * t2 + t) * h * slopes[i]
+                + (-2.0 * t3 + 3.0 * t2) * p1.y
+                + (t3 - t2) * h * slopes[i + 1];
+            points.push(DataPoint::new(
+                p0.x + t * h,
+                if step == steps { p1.y } else { y },
+            ));
+        }
+    }
+    points
+}
+
+/// Fit @data with @model. Returns None for FitModel::Lines which is
+/// handled by DataSeries::fit_lines().
+pub(super) fn fit(model: FitModel, data: &[DataPoint], shape: DataShape) -> Option<DataLines> {
+    let groups = group_by_x(data);
+    if model == FitModel::Lines || groups.is_empty() {
+        return None;
+    }
+    let xs: Vec<f64> = groups.iter().map(|g| g.0).collect();
+    let ys: Vec<f64> = groups.iter().map(|g| g.1).collect();
+    let ws: Vec<f64> = groups.iter().map(|g| g.2).collect();
+
+    let fitted = match model {
+        FitModel::Lowess => lowess(&xs, &ys, &ws, shape),
+        _ => isotonic(&ys, &ws, shape),
+    };
+    let mut points: Vec<DataPoint> = xs
+        .iter()
+        .zip(fitted.iter())
+        .map(|(x, y)| DataPoint::new(*x, *y))
+        .collect();
+
+    if model == FitModel::MonotoneSpline {
+        // Only keep the ends of flat stretches as knots so that they stay
+        // flat.
+        let knots: Vec<DataPoint> = (0..points.len())
+            .filter(|&i| {
+                i == 0
+                    || i == points.len() - 1
+                    || points[i - 1].y != points[i].y
+                    || points[i + 1].y != points[i].y
+            })
+            .map(|i| points[i])
+            .collect();
+        points = monotone_spline(&knots);
+    }
+
+    DataLines::new(&points).ok()
+}
+
+/// Goodness of fit of @lines to @data, the coefficient of determination.
+pub(super) fn r_squared(data: &[DataPoint], lines: &DataLines) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mean = data.iter().map(|pt| pt.y).sum::<f64>() / data.len() as f64;
+    let ss_tot: f64 = data.iter().map(|pt| (pt.y - mean).powi(2)).sum();
+    let ss_res: f64 = data
+        .iter()
+        .map(|pt| (pt.y - lines.eval(pt.x)).powi(2))
+        .sum();
+    if ss_tot == 0.0 {
+        if ss_res == 0.0 {
+            1.0
+        } else {
+            0.0
+        }
+    } else {
+        1.0 - ss_res / ss_tot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fit, r_squared, FitModel};
+    use super::{DataPoint, DataShape};
+
+    fn points(pts: &[(f64, f64)]) -> Vec<DataPoint> {
+        pts.iter().map(|(x, y)| DataPoint::new(*x, *y)).collect()
+    }
+
+    fn is_inc(pts: &[DataPoint]) -> bool {
+        pts.windows(2).all(|p| p[0].y <= p[1].y)
+    }
+
+    #[test]
+    fn test_iocost_tune_fit_models() {
+        let data = points(&[
+            (10.0, 1.0),
+            (20.0, 1.2),
+            (30.0, 1.1),
+            (40.0, 1.5),
+            (50.0, 2.5),
+            (50.0, 2.3),
+            (60.0, 4.0),
+            (70.0, 6.5),
+            (80.0, 6.0),
+            (90.0, 9.0),
+            (100.0, 13.0),
+        ]);
+
+        assert!(fit(FitModel::Lines, &data, DataShape::Inc).is_none());
+
+        let iso = fit(FitModel::Isotonic, &data, DataShape::Inc).unwrap();
+        assert_eq!(iso.range, (10.0, 100.0));
+        assert!(is_inc(&iso.points));
+        // The violating pairs are pooled and the duplicate vrate averaged.
+        assert_eq!(iso.eval(20.0), 1.15);
+        assert_eq!(iso.eval(30.0), 1.15);
+        assert_eq!(iso.eval(50.0), 2.4);
+        assert_eq!(iso.eval(70.0), 6.25);
+
+        for model in [FitModel::Lowess, FitModel::MonotoneSpline].iter() {
+            let lines = fit(*model, &data, DataShape::Inc).unwrap();
+            assert_eq!(lines.range, (10.0, 100.0));
+            assert!(is_inc(&lines.points), "{} not increasing", model);
+            assert!(r_squared(&data, &lines) > 0.95, "{} fit too poor", model);
+        }
+
+        let dec = fit(FitModel::Isotonic, &data, DataShape::Dec).unwrap();
+        assert!(dec.points.windows(2).all(|p| p[0].y >= p[1].y));
+
+        let peak = points(&[
+            (10.0, 1.0),
+            (20.0, 2.0),
+            (30.0, 3.0),
+            (40.0, 2.0),
+            (50.0, 1.0),
+        ]);
+        let lines = fit(FitModel::Isotonic, &peak, DataShape::SinglePeak).unwrap();
+        assert_eq!(r_squared(&peak, &lines), 1.0);
+
+        assert_eq!(FitModel::parse("Spline").unwrap(), FitModel::MonotoneSpline);
+        assert!(FitModel::parse("cubic").is_err());
+    }
+}
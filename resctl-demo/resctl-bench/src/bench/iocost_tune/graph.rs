@@ -199,6 +199,17 @@ impl<'a, 'b> Grapher<'a, 'b> {
             ),
         );
 
+        if let Some(interval) = series.interval.as_ref() {
+            for lines in [&interval.low, &interval.high] {
+                let segments: Vec<(f64, f64)> = lines
+                    .points
+                    .iter()
+                    .map(|pt| (pt.x, pt.y * yscale))
+                    .collect();
+                view = view.add(Plot::new(segments).line_style(LineStyle::new().colour("#a8b0f0")));
+            }
+        }
+
         let segments: Vec<(f64, f64)> = series
             .lines
             .points
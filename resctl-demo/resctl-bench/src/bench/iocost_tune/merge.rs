@@ -1,5 +1,8 @@
 use super::super::*;
-use super::{DataSel, DataSeries, IoCostTuneBench, IoCostTuneRecord, IoCostTuneResult};
+use super::{
+    DataLines, DataPoint, DataSel, DataSeries, IoCostTuneBench, IoCostTuneJob, IoCostTuneRecord,
+    IoCostTuneResult,
+};
 use statrs::distribution::{ContinuousCDF, Normal};
 use std::collections::{BTreeMap, HashMap, HashSet};
 
@@ -96,7 +99,105 @@ fn merge_model(
     (model_from_array(&medians), model_is_outlier)
 }
 
-pub fn merge(srcs: &mut Vec<MergeSrc>) -> Result<JobData> {
+/// The data series compared across the sources to find the ones which
+/// don't agree with the rest, e.g. because of a broken system.
+const SRC_OUTLIER_SELS: &[DataSel] = &[DataSel::MOF, DataSel::Isol];
+
+/// Comparing is meaningful only with enough sources.
+const SRC_OUTLIER_MIN_SRCS: usize = 3;
+
+/// A source is an outlier if the RMS error of its data against the lines
+/// fitted to the other sources is larger than SRC_OUTLIER_ERR_RATIO times
+/// the RMS error of the other sources and SRC_OUTLIER_MIN_REL_ERR of the
+/// mean value.
+const SRC_OUTLIER_ERR_RATIO: f64 = 3.0;
+const SRC_OUTLIER_MIN_REL_ERR: f64 = 0.05;
+
+fn all_points(ds: &DataSeries) -> impl Iterator<Item = &DataPoint> {
+    ds.data.iter().chain(ds.outliers.iter())
+}
+
+fn rms_error<'a, I>(points: I, lines: &DataLines) -> Option<f64>
+where
+    I: Iterator<Item = &'a DataPoint>,
+{
+    let errors: Vec<f64> = points.map(|pt| (pt.y - lines.eval(pt.x)).powi(2)).collect();
+    if errors.is_empty() {
+        None
+    } else {
+        Some(statistical::mean(&errors).sqrt())
+    }
+}
+
+/// Compare each of @results against the lines fitted to the rest of
+/// @results and return the indices of the outliers with why.
+fn src_outliers(results: &[(usize, &IoCostTuneResult)]) -> Result<Vec<(usize, String)>> {
+    let mut outliers = vec![];
+    if results.len() < SRC_OUTLIER_MIN_SRCS {
+        return Ok(outliers);
+    }
+
+    let job = IoCostTuneJob::default();
+    for (idx, res) in results.iter() {
+        let others = || results.iter().filter(move |(oidx, _)| oidx != idx);
+
+        // Fit the lines to the other sources.
+        let mut loo = BTreeMap::<DataSel, DataSeries>::default();
+        for sel in SRC_OUTLIER_SELS.iter() {
+            let mut ds = DataSeries::default();
+            for (_, ores) in others() {
+                if let Some(ods) = ores.data.get(sel) {
+                    ds.data.extend(all_points(ods));
+                }
+            }
+            ds.data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            loo.insert(sel.clone(), ds);
+        }
+        job.solve_data(&mut loo, res.isol_thr)?;
+
+        for sel in SRC_OUTLIER_SELS.iter() {
+            let (lines, ds) = match (&loo[sel].lines, res.data.get(sel)) {
+                (lines, Some(ds)) if !lines.points.is_empty() => (lines, ds),
+                _ => continue,
+            };
+            let (err, others_err) = match (
+                rms_error(all_points(ds), lines),
+                rms_error(loo[sel].data.iter(), lines),
+            ) {
+                (Some(err), Some(others_err)) => (err, others_err),
+                _ => continue,
+            };
+            let mean = statistical::mean(
+                &all_points(ds)
+                    .chain(loo[sel].data.iter())
+                    .map(|pt| pt.y.abs())
+                    .collect::<Vec<f64>>(),
+            );
+            trace!(
+                "src_outliers: idx={} sel={} err={} others_err={} mean={}",
+                idx,
+                sel,
+                err,
+                others_err,
+                mean
+            );
+
+            if err > others_err * SRC_OUTLIER_ERR_RATIO && err > mean * SRC_OUTLIER_MIN_REL_ERR {
+                outliers.push((
+                    *idx,
+                    format!(
+                        "{} is an outlier, error {:.3} vs. {:.3} of the other sources",
+                        sel, err, others_err
+                    ),
+                ));
+                break;
+            }
+        }
+    }
+    Ok(outliers)
+}
+
+pub fn merge(srcs: &mut Vec<MergeSrc>, bootstrap: u32) -> Result<JobData> {
     // We only care about distinct models. Weed out duplicates using HashSet.
     let models: HashSet<IoCostModelParams> = srcs
         .iter()
@@ -112,23 +213,42 @@ pub fn merge(srcs: &mut Vec<MergeSrc>) -> Result<JobData> {
         }
     }
 
+    let mut parsed: Vec<(usize, IoCostTuneRecord, IoCostTuneResult)> = vec![];
+    for (idx, src) in srcs.iter_mut().enumerate() {
+        if src.rejected.is_some() {
+            continue;
+        }
+        match (src.data.parse_record(), src.data.parse_result()) {
+            (Ok(rec), Ok(res)) => parsed.push((idx, rec, res)),
+            (Err(e), _) | (_, Err(e)) => {
+                src.rejected = Some(format!("failed to parse ({:?})", &e));
+                debug!(
+                    "iocost-tune-merge: {:?} rejected ({})",
+                    &src.file,
+                    src.rejected.as_ref().unwrap()
+                );
+            }
+        }
+    }
+
+    // Mark sources whose data doesn't agree with the rest.
+    let results: Vec<(usize, &IoCostTuneResult)> =
+        parsed.iter().map(|(idx, _, res)| (*idx, res)).collect();
+    for (idx, why) in src_outliers(&results)?.into_iter() {
+        debug!(
+            "iocost-tune-merge: {:?} rejected ({})",
+            &srcs[idx].file, &why
+        );
+        srcs[idx].rejected = Some(why);
+    }
+
     let mut first_valid = None;
     let mut data = BTreeMap::<DataSel, DataSeries>::default();
 
-    for src in srcs.iter_mut().filter(|src| src.rejected.is_none()) {
-        let (rec, res): (IoCostTuneRecord, IoCostTuneResult) =
-            match (src.data.parse_record(), src.data.parse_result()) {
-                (Ok(rec), Ok(res)) => (rec, res),
-                (Err(e), _) | (_, Err(e)) => {
-                    src.rejected = Some(format!("failed to parse ({:?})", &e));
-                    debug!(
-                        "iocost-tune-merge: {:?} rejected ({})",
-                        &src.file,
-                        src.rejected.as_ref().unwrap()
-                    );
-                    continue;
-                }
-            };
+    for (idx, rec, res) in parsed.into_iter() {
+        if srcs[idx].rejected.is_some() {
+            continue;
+        }
 
         match first_valid.as_ref() {
             None => first_valid = Some((rec.clone(), res.clone())),
@@ -162,11 +282,18 @@ pub fn merge(srcs: &mut Vec<MergeSrc>) -> Result<JobData> {
         IoCostTuneResult {
             data,
             solutions: Default::default(),
+            confidence: Default::default(),
             ..first_res
         },
     );
 
-    let dfl_spec = JobSpec::new("iocost-tune", None, None, JobSpec::props(&vec![]));
+    let bootstrap = bootstrap.to_string();
+    let dfl_spec = JobSpec::new(
+        "iocost-tune",
+        None,
+        None,
+        JobSpec::props(&[&[("bootstrap", &bootstrap)]]),
+    );
     let job = IoCostTuneBench {}.parse(&dfl_spec, None)?;
     let rec_json = serde_json::to_value(rec)?;
     let res_json = job.solve(rec_json.clone(), serde_json::to_value(res)?)?;
@@ -184,7 +311,7 @@ pub fn merge(srcs: &mut Vec<MergeSrc>) -> Result<JobData> {
 
 #[cfg(test)]
 mod tests {
-    use super::IoCostModelParams;
+    use super::{DataPoint, DataSel, DataSeries, IoCostModelParams, IoCostTuneResult};
     use std::collections::HashSet;
 
     #[test]
@@ -248,4 +375,41 @@ mod tests {
             1
         );
     }
+
+    #[test]
+    fn test_iocost_tune_src_outliers() {
+        let _ = ::env_logger::try_init();
+
+        // MOF rising from 1.0 to 1.5 until vrate 60 and flat after.
+        let mof = |vrate: f64| 1.0 + 0.5 * (vrate.min(60.0) / 60.0);
+        let result = |shift: f64, scale: f64| {
+            let mut res = IoCostTuneResult::default();
+            let mut ds = DataSeries::default();
+            for i in 0..20 {
+                let vrate = 10.0 + i as f64 * 5.0 + shift;
+                let noise = if i % 2 == 0 { 0.01 } else { -0.01 };
+                ds.data
+                    .push(DataPoint::new(vrate, mof(vrate) * scale + noise));
+            }
+            res.data.insert(DataSel::MOF, ds);
+            res
+        };
+
+        let results = vec![
+            result(0.0, 1.0),
+            result(1.0, 1.0),
+            result(2.0, 1.0),
+            result(3.0, 0.7),
+            result(4.0, 1.0),
+        ];
+        let results: Vec<(usize, &IoCostTuneResult)> = results.iter().enumerate().collect();
+
+        let outliers = super::src_outliers(&results).unwrap();
+        assert_eq!(outliers.len(), 1);
+        assert_eq!(outliers[0].0, 3);
+        assert!(outliers[0].1.starts_with("MOF is an outlier"));
+
+        // Too few sources to compare.
+        assert!(super::src_outliers(&results[3..]).unwrap().is_empty());
+    }
 }
@@ -0,0 +1,235 @@
+// Copyright (c) Facebook, Inc. and its affiliates.
+//! Sensitivity of iocost-tune solutions to the data.
+//!
+//! The data series are perturbed and the rules are solved again to see
+//! how much the resulting QoS parameters move. Two kinds of perturbations
+//! are used: jackknife, which leaves out one benchmark run at a time, and
+//! shifts, which move the values of one run up and then down by a small
+//! fraction. Merged results don't remember which source each run came
+//! from, so runs are the units. A solution which moves a lot is likely a
+//! fluke of a few data points.
+use super::super::*;
+use super::confidence::{points_by_run, solve_perturbed};
+use super::{DataPoint, DataSel, IoCostTuneJob, IoCostTuneResult, QoSRule};
+use std::collections::BTreeSet;
+
+/// Values of the shifted runs are multiplied by 1 +/- this by default.
+pub(super) const DFL_SHIFT: f64 = 0.05;
+
+/// A solution is unstable if a QoS parameter moves more than this
+/// fraction or if it couldn't be solved in more than 1 - STABLE_SOLVED_FRAC
+/// of the perturbations.
+const UNSTABLE_DEV: f64 = 0.1;
+const STABLE_SOLVED_FRAC: f64 = 0.9;
+
+/// The range of a QoS parameter across the perturbations.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct ParamRange {
+    base: f64,
+    low: f64,
+    high: f64,
+}
+
+impl ParamRange {
+    fn new(base: f64) -> Self {
+        Self {
+            base,
+            low: base,
+            high: base,
+        }
+    }
+
+    fn add(&mut self, val: f64) {
+        self.low = self.low.min(val);
+        self.high = self.high.max(val);
+    }
+
+    /// The largest move relative to the base value.
+    fn max_dev(&self) -> f64 {
+        if self.base == 0.0 {
+            if self.low == self.high {
+                0.0
+            } else {
+                std::f64::INFINITY
+            }
+        } else {
+            (self.high - self.base).max(self.base - self.low) / self.base.abs()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct Sensitivity {
+    pub(super) name: String,
+    pub(super) perturbations: u32,
+    pub(super) solved: u32,
+    pub(super) min: ParamRange,
+    pub(super) max: ParamRange,
+    pub(super) rlat: ParamRange,
+    pub(super) wlat: ParamRange,
+}
+
+impl Sensitivity {
+    fn params(&self) -> [(&'static str, &ParamRange); 4] {
+        [
+            ("min", &self.min),
+            ("max", &self.max),
+            ("rlat", &self.rlat),
+            ("wlat", &self.wlat),
+        ]
+    }
+
+    pub(super) fn max_dev(&self) -> f64 {
+        self.params()
+            .iter()
+            .map(|(_, range)| range.max_dev())
+            .fold(0.0, f64::max)
+    }
+
+    pub(super) fn is_stable(&self) -> bool {
+        self.solved as f64 >= self.perturbations as f64 * STABLE_SOLVED_FRAC
+            && self.max_dev() <= UNSTABLE_DEV
+    }
+
+    pub(super) fn format<'a>(&self, out: &mut Box<dyn Write + 'a>) {
+        writeln!(
+            out,
+            "[{}] solved={}/{} {} (max-dev={}%)",
+            &self.name,
+            self.solved,
+            self.perturbations,
+            if self.is_stable() {
+                "stable"
+            } else {
+                "UNSTABLE"
+            },
+            format_pct(self.max_dev())
+        )
+        .unwrap();
+        for (name, range) in self.params().iter() {
+            let fmt = |v: f64| match *name {
+                "rlat" | "wlat" => format_duration(v / 1_000_000.0),
+                _ => format!("{:.2}", v),
+            };
+            writeln!(
+                out,
+                "  {:<4}: {:>6} range={:>6}-{:<6} max-dev={}%",
+                name,
+                fmt(range.base),
+                fmt(range.low),
+                fmt(range.high),
+                format_pct(range.max_dev())
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// The data series which need to be solved for @rules.
+fn rule_sels(rules: &[&QoSRule]) -> BTreeSet<DataSel> {
+    let mut sels: BTreeSet<DataSel> = [
+        DataSel::MOF,
+        DataSel::AMOF,
+        // Required by filter_by_isol.
+        DataSel::Isol,
+        // Used for fixed vrate solutions.
+        DataSel::RLat("99".into(), "mean".into()),
+        DataSel::WLat("99".into(), "mean".into()),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    for rule in rules.iter() {
+        sels.extend(rule.target.sels().into_iter());
+    }
+    sels
+}
+
+/// Perturb the data of @res and solve @rules which must have been solved
+/// into @res.solutions again.
+pub(super) fn analyze(
+    job: &IoCostTuneJob,
+    res: &IoCostTuneResult,
+    rules: &[&QoSRule],
+    shift: f64,
+) -> Result<Vec<Sensitivity>> {
+    let rules: Vec<&QoSRule> = rules
+        .iter()
+        .filter(|rule| res.solutions.contains_key(&rule.name))
+        .cloned()
+        .collect();
+    let sels = rule_sels(&rules);
+    let (points, runs) = points_by_run(sels.iter().filter_map(|sel| res.data.get_key_value(sel)));
+
+    // (left out run, shifted run and how much), runs are indices into runs
+    let mut perturbations: Vec<(Option<usize>, Option<(usize, f64)>)> = vec![];
+    for run in 0..runs.len() {
+        perturbations.push((Some(run), None));
+        if shift != 0.0 {
+            perturbations.push((None, Some((run, 1.0 + shift))));
+            perturbations.push((None, Some((run, 1.0 - shift))));
+        }
+    }
+
+    let mut sensitivities: Vec<Sensitivity> = rules
+        .iter()
+        .map(|rule| {
+            let qos = &res.solutions[&rule.name].qos;
+            Sensitivity {
+                name: rule.name.clone(),
+                perturbations: perturbations.len() as u32,
+                solved: 0,
+                min: ParamRange::new(qos.min),
+                max: ParamRange::new(qos.max),
+                rlat: ParamRange::new(qos.rlat as f64),
+                wlat: ParamRange::new(qos.wlat as f64),
+            }
+        })
+        .collect();
+
+    for (left_out, shifted) in perturbations.iter() {
+        let data = solve_perturbed(job, res.isol_thr, &points, &runs, |idx, pt| {
+            match (left_out, shifted) {
+                (Some(run), _) if idx == *run => (0, *pt),
+                (_, Some((run, mult))) if idx == *run => (1, DataPoint::new(pt.x, pt.y * mult)),
+                _ => (1, *pt),
+            }
+        })?;
+
+        for (rule, sens) in rules.iter().zip(sensitivities.iter_mut()) {
+            if let Ok(Some((mut qos, target_vrate))) =
+                rule.target.solve(&data, (job.scale_min, job.scale_max))
+            {
+                IoCostTuneJob::scale_qos(&mut qos, target_vrate / 100.0);
+                sens.solved += 1;
+                sens.min.add(qos.min);
+                sens.max.add(qos.max);
+                sens.rlat.add(qos.rlat as f64);
+                sens.wlat.add(qos.wlat as f64);
+            }
+        }
+    }
+
+    Ok(sensitivities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ParamRange;
+
+    #[test]
+    fn test_iocost_tune_sensitivity_param_range() {
+        let mut range = ParamRange::new(100.0);
+        assert_eq!(range.max_dev(), 0.0);
+        range.add(95.0);
+        range.add(102.0);
+        assert_eq!((range.low, range.high), (95.0, 102.0));
+        assert_eq!(range.max_dev(), 0.05);
+
+        let mut zero = ParamRange::new(0.0);
+        zero.add(0.0);
+        assert_eq!(zero.max_dev(), 0.0);
+        zero.add(1.0);
+        assert!(zero.max_dev().is_infinite());
+    }
+}
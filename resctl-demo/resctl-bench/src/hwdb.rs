@@ -0,0 +1,544 @@
+// Copyright (c) Facebook, Inc. and its affiliates.
+//! Parser and linter for the udev hwdb files generated by `format
+//! iocost-tune:hwdb`, e.g. iocost-benchmarks' 90-iocost-tune.hwdb.
+//!
+//! The format is a sequence of stanzas, each made of one or more match
+//! lines followed by indented properties:
+//!
+//!   # <datapoints summary>
+//!   block:*:name:<model>:fwrev:<fwrev>:
+//!     IOCOST_SOLUTIONS=isolation isolated-bandwidth bandwidth naive
+//!     IOCOST_MODEL_ISOLATION=rbps=... rseqiops=... ...
+//!     IOCOST_QOS_ISOLATION=rpct=... rlat=... ...
+//!     ...
+use anyhow::{anyhow, bail, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use super::model_name;
+use rd_util::{IoCostModelParams, IoCostQoSParams};
+
+const MODEL_PREFIX: &str = "IOCOST_MODEL_";
+const QOS_PREFIX: &str = "IOCOST_QOS_";
+const SOLUTIONS_KEY: &str = "IOCOST_SOLUTIONS";
+
+/// Converts a solution name as listed in IOCOST_SOLUTIONS to the form used
+/// in the IOCOST_MODEL_ and IOCOST_QOS_ property names.
+pub fn solution_property_name(name: &str) -> String {
+    name.to_uppercase().replace('-', "_")
+}
+
+/// A `block:<devpath>:name:<model>:fwrev:<fwrev>:` match line.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HwdbMatch {
+    pub devpath: String,
+    pub model: String,
+    pub fwrev: String,
+}
+
+impl HwdbMatch {
+    fn parse(line: &str) -> Result<Self> {
+        let rest = line
+            .strip_prefix("block:")
+            .and_then(|rest| rest.strip_suffix(':'))
+            .ok_or_else(|| anyhow!("match line doesn't look like \"block:...:\""))?;
+        let (rest, fwrev) = rest
+            .rsplit_once(":fwrev:")
+            .ok_or_else(|| anyhow!("match line without fwrev"))?;
+        let (devpath, model) = rest
+            .split_once(":name:")
+            .ok_or_else(|| anyhow!("match line without name"))?;
+        Ok(Self {
+            devpath: devpath.into(),
+            model: model.into(),
+            fwrev: fwrev.into(),
+        })
+    }
+}
+
+impl fmt::Display for HwdbMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "block:{}:name:{}:fwrev:{}:",
+            &self.devpath, &self.model, &self.fwrev
+        )
+    }
+}
+
+/// A group of match lines and the properties which apply to them.
+#[derive(Debug, Clone, Default)]
+pub struct HwdbStanza {
+    /// Line number of the first match line
+    pub line: usize,
+    pub matches: Vec<HwdbMatch>,
+    /// Solution names as listed in IOCOST_SOLUTIONS
+    pub solutions: Vec<String>,
+    /// Model and QoS parameters keyed by the property name of the solution,
+    /// e.g. ISOLATED_BANDWIDTH. This includes solutions which aren't listed
+    /// in IOCOST_SOLUTIONS such as the rlat ones.
+    pub models: BTreeMap<String, IoCostModelParams>,
+    pub qos: BTreeMap<String, IoCostQoSParams>,
+    /// Line numbers of the properties, used for reporting
+    prop_lines: HashMap<String, usize>,
+}
+
+impl HwdbStanza {
+    /// Returns the model and QoS parameters of solution `name`, which can
+    /// be given either as listed in IOCOST_SOLUTIONS or as its property name.
+    pub fn solution(&self, name: &str) -> Option<(&IoCostModelParams, &IoCostQoSParams)> {
+        let name = solution_property_name(name);
+        match (self.models.get(&name), self.qos.get(&name)) {
+            (Some(model), Some(qos)) => Some((model, qos)),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the stanza applies to a device with `model` and
+    /// `fwrev`. Only the "*" wildcard in fwrev is interpreted, the same way
+    /// the generated files use it.
+    pub fn matches(&self, model: &str, fwrev: &str) -> bool {
+        self.matches
+            .iter()
+            .any(|m| m.model == model && (m.fwrev == "*" || m.fwrev == fwrev))
+    }
+}
+
+/// A problem found while parsing or linting a hwdb file.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HwdbProblem {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for HwdbProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, &self.msg)
+    }
+}
+
+/// A parsed hwdb file.
+#[derive(Debug, Clone, Default)]
+pub struct Hwdb {
+    pub stanzas: Vec<HwdbStanza>,
+    /// Problems found while parsing, see lint() for the full list.
+    pub problems: Vec<HwdbProblem>,
+}
+
+/// Parses `value` as space separated key=value pairs which must be `keys`
+/// in order.
+fn parse_keyed<'a>(value: &'a str, keys: &[&str]) -> Result<Vec<&'a str>> {
+    let pairs: Vec<&str> = value.split(' ').collect();
+    if pairs.len() != keys.len() {
+        bail!(
+            "expected {} key=value pairs, got {}",
+            keys.len(),
+            pairs.len()
+        );
+    }
+    let mut vals = vec![];
+    for (pair, key) in pairs.iter().zip(keys.iter()) {
+        match pair.split_once('=') {
+            Some((k, v)) if k == *key => vals.push(v),
+            _ => bail!("expected {}=VAL, got {:?}", key, pair),
+        }
+    }
+    Ok(vals)
+}
+
+fn parse_num<T>(key: &str, val: &str) -> Result<T>
+where
+    T: std::str::FromStr,
+{
+    val.parse::<T>()
+        .map_err(|_| anyhow!("invalid {} value {:?}", key, val))
+}
+
+/// Parses an IOCOST_MODEL_ value, which must be formatted exactly as
+/// IoCostModelParams would format it.
+pub fn parse_model(value: &str) -> Result<IoCostModelParams> {
+    const KEYS: &[&str] = &[
+        "rbps",
+        "rseqiops",
+        "rrandiops",
+        "wbps",
+        "wseqiops",
+        "wrandiops",
+    ];
+    let v = parse_keyed(value, KEYS)?;
+    let model = IoCostModelParams {
+        rbps: parse_num(KEYS[0], v[0])?,
+        rseqiops: parse_num(KEYS[1], v[1])?,
+        rrandiops: parse_num(KEYS[2], v[2])?,
+        wbps: parse_num(KEYS[3], v[3])?,
+        wseqiops: parse_num(KEYS[4], v[4])?,
+        wrandiops: parse_num(KEYS[5], v[5])?,
+    };
+    if format!("{}", &model) != value {
+        bail!("doesn't round-trip, formats as {:?}", format!("{}", &model));
+    }
+    Ok(model)
+}
+
+/// Parses an IOCOST_QOS_ value, which must be formatted exactly as
+/// IoCostQoSParams would format it.
+pub fn parse_qos(value: &str) -> Result<IoCostQoSParams> {
+    const KEYS: &[&str] = &["rpct", "rlat", "wpct", "wlat", "min", "max"];
+    let v = parse_keyed(value, KEYS)?;
+    let qos = IoCostQoSParams {
+        rpct: parse_num(KEYS[0], v[0])?,
+        rlat: parse_num(KEYS[1], v[1])?,
+        wpct: parse_num(KEYS[2], v[2])?,
+        wlat: parse_num(KEYS[3], v[3])?,
+        min: parse_num(KEYS[4], v[4])?,
+        max: parse_num(KEYS[5], v[5])?,
+    };
+    if format!("{}", &qos) != value {
+        bail!("doesn't round-trip, formats as {:?}", format!("{}", &qos));
+    }
+    Ok(qos)
+}
+
+impl Hwdb {
+    /// Parses `text`. Lines which can't be parsed are recorded in
+    /// `problems` and otherwise ignored so that a single pass reports as
+    /// much as possible.
+    pub fn parse(text: &str) -> Self {
+        let mut hwdb = Self::default();
+        let mut problems = vec![];
+        let mut cur: Option<HwdbStanza> = None;
+        let mut in_props = false;
+
+        for (idx, line) in text.lines().enumerate() {
+            let lnr = idx + 1;
+            let mut problem = |msg: String| problems.push(HwdbProblem { line: lnr, msg });
+
+            if line.trim().is_empty() {
+                if let Some(stanza) = cur.take() {
+                    hwdb.stanzas.push(stanza);
+                }
+                in_props = false;
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+
+            if !line.starts_with(' ') {
+                // A match line after properties starts a new stanza.
+                if in_props {
+                    hwdb.stanzas.extend(cur.take());
+                    in_props = false;
+                }
+                let stanza = cur.get_or_insert_with(|| HwdbStanza {
+                    line: lnr,
+                    ..Default::default()
+                });
+                match HwdbMatch::parse(line) {
+                    Ok(m) => stanza.matches.push(m),
+                    Err(e) => problem(format!("{:#}", &e)),
+                }
+                continue;
+            }
+
+            let stanza = match cur.as_mut() {
+                Some(stanza) => stanza,
+                None => {
+                    problem("property outside of a stanza".into());
+                    continue;
+                }
+            };
+            in_props = true;
+
+            let (key, value) = match line.trim_start().split_once('=') {
+                Some(kv) => kv,
+                None => {
+                    problem(format!("expected KEY=VALUE, got {:?}", line.trim_start()));
+                    continue;
+                }
+            };
+            if line.ends_with(char::is_whitespace) {
+                problem(format!("trailing whitespace in {}", key));
+            }
+            if stanza.prop_lines.insert(key.into(), lnr).is_some() {
+                problem(format!("duplicate property {}", key));
+                continue;
+            }
+
+            if key == SOLUTIONS_KEY {
+                stanza.solutions = value.split_whitespace().map(str::to_string).collect();
+            } else if let Some(name) = key.strip_prefix(MODEL_PREFIX) {
+                match parse_model(value) {
+                    Ok(model) => {
+                        stanza.models.insert(name.into(), model);
+                    }
+                    Err(e) => problem(format!("{}: {:#}", key, &e)),
+                }
+            } else if let Some(name) = key.strip_prefix(QOS_PREFIX) {
+                match parse_qos(value) {
+                    Ok(qos) => {
+                        stanza.qos.insert(name.into(), qos);
+                    }
+                    Err(e) => problem(format!("{}: {:#}", key, &e)),
+                }
+            } else {
+                problem(format!("unknown property {}", key));
+            }
+        }
+        hwdb.stanzas.extend(cur.take());
+        hwdb.problems = problems;
+        hwdb
+    }
+
+    /// Returns the stanza which applies to a device with `model` and
+    /// `fwrev`. Stanzas for the specific fwrev take precedence over the
+    /// ones for all fwrevs.
+    pub fn lookup(&self, model: &str, fwrev: &str) -> Option<&HwdbStanza> {
+        self.stanzas
+            .iter()
+            .find(|s| {
+                s.matches
+                    .iter()
+                    .any(|m| m.model == model && m.fwrev == fwrev)
+            })
+            .or_else(|| self.stanzas.iter().find(|s| s.matches(model, fwrev)))
+    }
+
+    /// Returns the parse problems along with the ones found by checking
+    /// the consistency of the stanzas, sorted by line.
+    pub fn lint(&self) -> Vec<HwdbProblem> {
+        let mut problems = self.problems.clone();
+        let mut seen: HashMap<&HwdbMatch, usize> = HashMap::new();
+
+        for stanza in self.stanzas.iter() {
+            let mut problem = |line: usize, msg: String| problems.push(HwdbProblem { line, msg });
+            let prop_line = |key: &str| stanza.prop_lines.get(key).copied().unwrap_or(stanza.line);
+
+            if stanza.matches.is_empty() {
+                problem(stanza.line, "stanza without a valid match line".into());
+            }
+            for m in stanza.matches.iter() {
+                if let Some(prev) = seen.insert(m, stanza.line) {
+                    problem(
+                        stanza.line,
+                        format!("duplicate match {}, previously on line {}", m, prev),
+                    );
+                }
+                let key = model_name::hwdb_key(&m.model);
+                if key != m.model {
+                    problem(
+                        stanza.line,
+                        format!(
+                            "model name {:?} isn't escaped, expected {:?}",
+                            &m.model, &key
+                        ),
+                    );
+                }
+            }
+
+            if !stanza.prop_lines.contains_key(SOLUTIONS_KEY) {
+                problem(stanza.line, format!("missing {}", SOLUTIONS_KEY));
+            }
+            for name in stanza.solutions.iter() {
+                let prop_name = solution_property_name(name);
+                for prefix in [MODEL_PREFIX, QOS_PREFIX] {
+                    if !stanza
+                        .prop_lines
+                        .contains_key(&format!("{}{}", prefix, &prop_name))
+                    {
+                        problem(
+                            prop_line(SOLUTIONS_KEY),
+                            format!("solution {:?} has no {}{}", name, prefix, &prop_name),
+                        );
+                    }
+                }
+            }
+            for (key, line) in stanza.prop_lines.iter() {
+                let other = if let Some(name) = key.strip_prefix(MODEL_PREFIX) {
+                    format!("{}{}", QOS_PREFIX, name)
+                } else if let Some(name) = key.strip_prefix(QOS_PREFIX) {
+                    format!("{}{}", MODEL_PREFIX, name)
+                } else {
+                    continue;
+                };
+                if !stanza.prop_lines.contains_key(&other) {
+                    problem(*line, format!("{} without {}", key, other));
+                }
+            }
+        }
+
+        problems.sort();
+        problems
+    }
+}
+
+/// Returns `text` without the match lines for which `keep` returns false.
+/// Stanzas which lose all their match lines are dropped along with the
+/// comments preceding them.
+pub fn filter_matches<F>(text: &str, mut keep: F) -> String
+where
+    F: FnMut(&HwdbMatch) -> bool,
+{
+    let is_match =
+        |line: &str| !line.is_empty() && !line.starts_with('#') && !line.starts_with(' ');
+
+    // Split into stanzas, each with the comments preceding it and the
+    // blank lines following it.
+    let mut stanzas: Vec<Vec<&str>> = vec![vec![]];
+    let mut in_props = false;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_end_matches('\n');
+        if in_props && !trimmed.is_empty() && !trimmed.starts_with(' ') {
+            stanzas.push(vec![]);
+            in_props = false;
+        }
+        in_props |= trimmed.starts_with(' ');
+        stanzas.last_mut().unwrap().push(line);
+    }
+
+    let mut out = String::new();
+    for lines in stanzas.iter() {
+        let mut had_matches = false;
+        let mut kept = vec![];
+        for line in lines.iter() {
+            let trimmed = line.trim_end_matches('\n');
+            if is_match(trimmed) {
+                had_matches = true;
+                if let Ok(m) = HwdbMatch::parse(trimmed) {
+                    if !keep(&m) {
+                        continue;
+                    }
+                }
+            }
+            kept.push(*line);
+        }
+        if !had_matches
+            || kept
+                .iter()
+                .any(|line| is_match(line.trim_end_matches('\n')))
+        {
+            out.extend(kept);
+        }
+    }
+    out
+}
+
+/// Parses and lints `text`, failing with all the problems found if there
+/// are any.
+pub fn check(text: &str) -> Result<Hwdb> {
+    let hwdb = Hwdb::parse(text);
+    let problems = hwdb.lint();
+    if !problems.is_empty() {
+        bail!(
+            "{} problem(s) found:\n{}",
+            problems.len(),
+            problems
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+    Ok(hwdb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STANZA: &str = "\
+# 10 data points
+block:*:name:CT1000P3PSSD8:fwrev:*:
+  IOCOST_SOLUTIONS=isolation naive
+  IOCOST_MODEL_ISOLATION=rbps=1000 rseqiops=200 rrandiops=100 wbps=900 wseqiops=180 wrandiops=90
+  IOCOST_QOS_ISOLATION=rpct=95.00 rlat=1000 wpct=95.00 wlat=2000 min=50.00 max=100.00
+  IOCOST_MODEL_NAIVE=rbps=1000 rseqiops=200 rrandiops=100 wbps=900 wseqiops=180 wrandiops=90
+  IOCOST_QOS_NAIVE=rpct=0.00 rlat=0 wpct=0.00 wlat=0 min=75.00 max=100.00
+  IOCOST_MODEL_RLAT_99_Q1=rbps=1000 rseqiops=200 rrandiops=100 wbps=900 wseqiops=180 wrandiops=90
+  IOCOST_QOS_RLAT_99_Q1=rpct=99.00 rlat=500 wpct=0.00 wlat=0 min=1.00 max=100.00
+";
+
+    #[test]
+    fn test_hwdb_parse() {
+        let hwdb = check(STANZA).unwrap();
+        assert_eq!(hwdb.stanzas.len(), 1);
+        let stanza = &hwdb.stanzas[0];
+        assert_eq!(stanza.line, 2);
+        assert_eq!(stanza.matches[0].model, "CT1000P3PSSD8");
+        assert_eq!(stanza.matches[0].fwrev, "*");
+        assert_eq!(stanza.solutions, vec!["isolation", "naive"]);
+        assert_eq!(stanza.models.len(), 3);
+
+        let (model, qos) = stanza.solution("rlat-99-q1").unwrap();
+        assert_eq!(model.rrandiops, 100);
+        assert_eq!(qos.rlat, 500);
+        assert_eq!(qos.min, 1.0);
+
+        assert!(hwdb.lookup("CT1000P3PSSD8", "P9CR30A").is_some());
+        assert!(hwdb.lookup("CT1000P3PSSD9", "P9CR30A").is_none());
+    }
+
+    #[test]
+    fn test_hwdb_lookup_fwrev() {
+        let text = format!(
+            "{}\n{}",
+            STANZA,
+            STANZA.replace("fwrev:*:", "fwrev:P9CR30A:")
+        );
+        let hwdb = check(&text).unwrap();
+        assert_eq!(hwdb.lookup("CT1000P3PSSD8", "P9CR30A").unwrap().line, 12);
+        assert_eq!(hwdb.lookup("CT1000P3PSSD8", "P9CR40A").unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_hwdb_lint() {
+        let text = format!(
+            "{}\n{}",
+            STANZA,
+            STANZA
+                .replace("  IOCOST_QOS_NAIVE=", "  IOCOST_QOS_NAIVEX=")
+                .replace(
+                    "rbps=1000 rseqiops=200 rrandiops=100",
+                    "rbps=1e3 rseqiops=200"
+                )
+                .replace("rpct=95.00", "rpct=95")
+        );
+        let lines: Vec<usize> = Hwdb::parse(&text).lint().iter().map(|p| p.line).collect();
+        // Duplicate match, solution without QoS, three bad model lines, a
+        // bad QoS line, a model without QoS and a QoS without model.
+        assert_eq!(lines, vec![12, 13, 14, 15, 16, 16, 17, 18]);
+
+        let problems = Hwdb::parse("block:*:name:WD*:fwrev:*:\n").lint();
+        assert!(problems.iter().any(|p| p.msg.contains("escaped")));
+        assert!(problems.iter().any(|p| p.msg.contains("IOCOST_SOLUTIONS")));
+        assert!(Hwdb::parse("  IOCOST_SOLUTIONS=naive\n").lint()[0]
+            .msg
+            .contains("outside"));
+    }
+
+    #[test]
+    fn test_hwdb_filter_matches() {
+        let fw_stanza = STANZA.replace("fwrev:*:", "fwrev:P9CR30A:");
+        let text = format!(
+            "{}\n{}",
+            STANZA.replace(
+                "fwrev:*:\n",
+                "fwrev:*:\nblock:*:name:CT1000P3PSSD9:fwrev:*:\n"
+            ),
+            &fw_stanza
+        );
+
+        let out = filter_matches(&text, |m| m.model != "CT1000P3PSSD9");
+        assert_eq!(out, format!("{}\n{}", STANZA, &fw_stanza));
+
+        // Stanzas don't have to be separated by blank lines.
+        let out = filter_matches(&format!("{}{}", STANZA, &fw_stanza), |m| {
+            m.fwrev != "P9CR30A"
+        });
+        assert_eq!(out, STANZA);
+
+        let out = filter_matches(&text, |m| m.fwrev != "*");
+        assert_eq!(out, fw_stanza);
+        assert_eq!(filter_matches(&text, |_| false), "");
+    }
+}
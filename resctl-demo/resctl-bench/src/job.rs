@@ -26,7 +26,29 @@ pub struct FormatOpts<'a> {
     pub result_path: &'a str,
 }
 
-pub trait Job {
+impl<'a> FormatOpts<'a> {
+    /// For high level summaries and hwdb we don't want to add a lot of
+    /// boiler plate. Let the job itself decide everything that should be
+    /// printed.
+    pub fn wants_undecorated(props: &JobProps) -> bool {
+        let mut is_high_level = false;
+        let mut is_hwdb = false;
+        for map in props.iter() {
+            if let Some(v) = map.get("high-level") {
+                is_high_level = v.len() == 0 || v.parse::<bool>().unwrap_or(false);
+            }
+            if let Some(v) = map.get("hwdb") {
+                is_hwdb = v.len() == 0 || v.parse::<bool>().unwrap_or(false);
+            }
+            if let Some(v) = map.get("hwdb-fwrev") {
+                is_hwdb = v.len() == 0 || v.parse::<bool>().unwrap_or(false);
+            }
+        }
+        is_high_level || is_hwdb
+    }
+}
+
+pub trait Job: Send + Sync {
     fn sysreqs(&self) -> BTreeSet<SysReq>;
 
     fn pre_run(&mut self, _rctx: &mut RunCtx) -> Result<()> {
@@ -592,6 +614,27 @@ impl JobCtxs {
         }
     }
 
+    /// Format the result matching @spec the same way `resctl-bench format`
+    /// would and return the output. @result_path is used to derive default
+    /// output file names, e.g. for pdf.
+    pub fn format_matching(&self, spec: &JobSpec, result_path: &str) -> Result<String> {
+        let jctx = match self.find_matching_jctx_idx(spec) {
+            Some(idx) => &self.vec[idx],
+            None => bail!("No matching result for {}", spec),
+        };
+        if jctx.data.result.is_none() {
+            bail!("{} doesn't have result", spec);
+        }
+
+        let opts = FormatOpts {
+            full: true,
+            undecorated: FormatOpts::wants_undecorated(&spec.props),
+            rstat: 0,
+            result_path,
+        };
+        jctx.format(&opts, &spec.props)
+    }
+
     pub fn load_results(path: &str) -> Result<Self> {
         let mut f = fs::OpenOptions::new().read(true).open(path)?;
         let mut buf = Vec::<u8>::new();
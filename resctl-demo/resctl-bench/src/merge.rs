@@ -90,7 +90,10 @@ fn dump_srcs(mid: &MergeId, srcs: &[MergeSrc]) {
     print!("{}", buf);
 }
 
-pub fn merge(args: &Args) -> Result<()> {
+/// Merge the result files in `args.merge_srcs` and return the merged jobs.
+/// The first entry is the merge-info record describing how the merge came
+/// to be. Nothing is printed or saved.
+pub fn merge_jobs(args: &Args) -> Result<JobCtxs> {
     let mut src_sets = BTreeMap::<MergeId, Vec<MergeSrc>>::new();
     for file in args.merge_srcs.iter() {
         let jctxs = JobCtxs::load_results(file)?;
@@ -130,7 +133,7 @@ pub fn merge(args: &Args) -> Result<()> {
     for (mid, srcs) in src_sets.iter_mut() {
         let bench = srcs[0].bench.clone();
         debug!("merging {:?} from {:?}", &mid, &srcs);
-        match bench.merge(srcs) {
+        match bench.merge(args, srcs) {
             Ok(res) => {
                 merged.insert(mid.clone(), (res, Default::default()));
             }
@@ -199,8 +202,8 @@ pub fn merge(args: &Args) -> Result<()> {
         info.merges.push(ent);
     }
 
-    // Create a fake merge-info record, print out and put it at the head of
-    // JobCtxs so that there's a record of how the merged result came to be.
+    // Create a fake merge-info record and put it at the head of JobCtxs so
+    // that there's a record of how the merged result came to be.
     let now = unix_now();
     let merge_info_job = JobCtx::with_job_data(JobData {
         spec: JobSpec::new("merge-info", None, None, JobSpec::props(&[])),
@@ -210,7 +213,14 @@ pub fn merge(args: &Args) -> Result<()> {
         result: Some(serde_json::to_value(true)?),
     })?;
 
-    merge_info_job
+    jobs.vec.insert(0, merge_info_job);
+    Ok(jobs)
+}
+
+pub fn merge(args: &Args) -> Result<()> {
+    let jobs = merge_jobs(args)?;
+
+    jobs.vec[0]
         .print(
             &FormatOpts {
                 full: true,
@@ -222,8 +232,6 @@ pub fn merge(args: &Args) -> Result<()> {
         )
         .unwrap();
 
-    jobs.vec.insert(0, merge_info_job);
-
     jobs.save_results(&args.result);
     Ok(())
 }
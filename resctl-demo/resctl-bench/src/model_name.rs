@@ -0,0 +1,114 @@
+// Copyright (c) Facebook, Inc. and its affiliates.
+//! Normalization and escaping of the device model strings reported in
+//! sysreqs_report.scr_dev_model. Everything which derives a name from a
+//! model string, e.g. the hwdb match keys and the iocost-benchmarks
+//! database directories, should go through here so that the same device
+//! always maps to the same names.
+
+/// Characters which udev hwdb interprets as glob patterns in match keys.
+/// ':' is also escaped as it separates the fields of the match keys.
+const HWDB_SPECIAL_CHARS: &[char] = &['*', '?', '[', ']', ':'];
+
+/// Returns the normalized form of `model`, without leading or trailing
+/// whitespace, which the kernel may pad model strings with.
+pub fn normalize(model: &str) -> String {
+    model.trim().to_string()
+}
+
+/// Returns `model` normalized and escaped for use as a field of a hwdb
+/// match key. The special characters are replaced with "?", which matches
+/// any single character including the original one, so the key still
+/// matches the device without ever matching a differently sized name.
+pub fn hwdb_key(model: &str) -> String {
+    normalize(model).replace(HWDB_SPECIAL_CHARS, "?")
+}
+
+/// Returns `model` normalized and converted into a name which is safe to
+/// use as a single path component, e.g. for the database directories.
+/// Whitespace is converted to "_" as the database has always done, and so
+/// is anything other than ASCII alphanumerics, "-", "_" and ".". Names
+/// made only of dots are converted as well so that they can't refer to
+/// the current or parent directory, and an empty name becomes "_".
+pub fn dir_name(model: &str) -> String {
+    let name: String = normalize(model)
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    if name.is_empty() {
+        "_".into()
+    } else if name.chars().all(|c| c == '.') {
+        name.replace('.', "_")
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The database directories of iocost-benchmarks/database/2.2.
+    const DB_2_2_MODELS: &[&str] = &[
+        "CT1000P3PSSD8",
+        "Fanxiang_S500Pro_1TB",
+        "HFS256GD9TNG-62A0A",
+        "HFS512GD9TNG-62A0A",
+        "HFS512GDE9X083N",
+        "INTEL_SSDPEKKA256G7",
+        "KINGSTON_SA2000M8250G",
+        "KXG50ZNV256G_TOSHIBA",
+        "KXG6AZNV512G_TOSHIBA_SAMPLE",
+        "Lexar_SSD_NM790_2TB",
+        "MTFDHBA256TCK-1AS15ABYY",
+        "MTFDHBA512TCK",
+        "MTFDKBA512TFH",
+        "MZUL2256HCHQ-00AFB",
+        "MZUL2512HCJQ-00AFB",
+        "Patriot_M.2_P300_1024GB",
+        "SAMSUNG_MZVLW256HEHP-00000",
+        "SHGP31-1000GM-2",
+        "SKhynix_Phoenix",
+        "Samsung_SSD_860",
+        "Samsung_SSD_870",
+        "Samsung_SSD_970_EVO_500GB",
+        "Samsung_SSD_970_PRO_512GB",
+        "Samsung_SSD_980_PRO_1TB",
+        "Samsung_SSD_990_EVO_1TB",
+        "Samsung_SSD_990_PRO_2TB",
+        "WDC_CL_SN720_SDAQNTW-256G-1020",
+        "WDC_CL_SN720_SDAQNTW-512G-1020",
+        "WDC_CL_SN720_SDAQNTW-512G-2000",
+        "WDC_CL_SN730_SDBQNTY-256G-2020",
+        "WDC_CL_SN730_SDBQNTY-512G-2020",
+        "WD_BLACK_SN770_1TB",
+        "WD_BLACK_SN850X_1000GB",
+        "WD_Blue_SN580_1TB",
+    ];
+
+    #[test]
+    fn test_model_name_database() {
+        for dir in DB_2_2_MODELS {
+            // The directories were named by replacing spaces with "_".
+            let model = dir.replace('_', " ");
+            assert_eq!(&dir_name(&model), dir);
+            assert_eq!(&dir_name(dir), dir);
+            assert_eq!(hwdb_key(&model), model);
+            // Padded model strings map to the same names.
+            assert_eq!(&dir_name(&format!("{}   ", &model)), dir);
+            assert_eq!(hwdb_key(&format!("{}\n", &model)), model);
+        }
+    }
+
+    #[test]
+    fn test_model_name_escape() {
+        assert_eq!(hwdb_key("WD*[SN]:770? "), "WD??SN??770?");
+        assert_eq!(dir_name(" WD*[SN]:770? "), "WD__SN__770_");
+        assert_eq!(dir_name("../../etc"), ".._.._etc");
+        assert_eq!(dir_name(".."), "__");
+        assert_eq!(dir_name("a/b\\c"), "a_b_c");
+        assert_eq!(dir_name(" "), "_");
+    }
+}